@@ -9,23 +9,78 @@
 //! - Second, the actual decoded objects themselves.
 //!
 //! Any asset which is so critical that it must never be unloaded may be pinned with [AssetCache::cache_always], at
-//! which point it may only be removed with [AssetCache::remove_key].
+//! which point it may only be removed with [AssetCache::remove] or [AssetCache::unpin].  Assets which are only needed
+//! for a while can be pinned with [AssetCache::pin], which keeps them until the last [PinGuard] is dropped.
+//!
+//! Items can be tagged as they are loaded with [AssetCache::get_tagged], for example with the level or menu which
+//! uses them, so that they can later be evicted or pinned together.
+//!
+//! Keys are `str` by default, but the cache can be keyed by any [CacheKey], such as interned ids, given a [Vfs] for
+//! that key type.  A [ParamKey] adds parameters for the [Decoder] to a path, so that one file can be decoded several
+//! ways while its bytes are only cached once.
+use std::borrow::Borrow;
 use std::io::{Error as IoError, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
+use crate::asset_handle::HandleSlot;
+use crate::tag_index::TagIndex;
+use crate::transition::WorkingSet;
 use crate::*;
 
-type CacheHashMap<V> = std::collections::HashMap<String, V, ahash::RandomState>;
+type CacheHashMap<K, V> = std::collections::HashMap<Arc<K>, V, ahash::RandomState>;
+
+type TagHashMap<V> = std::collections::HashMap<String, V, ahash::RandomState>;
+
+/// Items returned alongside their keys, e.g. from [AssetCache::get_all].
+type KeyedItems<T> = Vec<(String, Arc<T>)>;
+
+/// What [AssetCache::get_or_fallback] returns.
+type FallbackResult<O, E> = Result<WithFallback<O, E>, AssetCacheError<E>>;
+
+/// Picks a key to fall back to when loading a key fails.  See [AssetCache::with_fallback_resolver].
+type FallbackResolver<K, E> =
+    dyn Fn(&K, &AssetCacheError<E>) -> Option<<K as CacheKey>::Owned> + Send + Sync;
+
+/// A single budget shared by the bytes and decoded tiers of an [AssetCache], set with
+/// [AssetCacheConfig::unified_budget].
+///
+/// When the total is exceeded, the cache evicts from whichever tier's least recently used entry is oldest, after
+/// dividing its age by the tier's priority.  With equal priorities this is a plain LRU across both tiers.  Raising
+/// `decoded_priority` keeps decoded outputs around longer than bytes, which are usually cheaper to get back.
+///
+/// Neither tier is evicted below its floor, so the total can be exceeded if the floors add up to more than it.
+#[derive(Clone, Debug, derive_builder::Builder)]
+pub struct UnifiedBudget {
+    /// Maximum total cost of both tiers together.
+    pub total_cost: u64,
+    #[builder(default = "1")]
+    pub bytes_priority: u64,
+    #[builder(default = "1")]
+    pub decoded_priority: u64,
+    /// Cost the bytes tier is allowed to keep regardless of age.
+    #[builder(default)]
+    pub bytes_floor: u64,
+    /// Cost the decoded tier is allowed to keep regardless of age.
+    #[builder(default)]
+    pub decoded_floor: u64,
+}
 
 /// Configuration for a [AssetCache].
 ///
 /// This type doesn't implement `Default`: applications should carefully consider their memory requirements and decide
-/// on appropriate values.
+/// on appropriate values.  Build it with [AssetCacheConfigBuilder], which fills in defaults for the optional fields; it
+/// is `#[non_exhaustive]` so that new options aren't breaking changes.
+///
+/// Building fails if a single object limit is above the low watermark of its tier, since such an object would evict
+/// everything else in the tier on its own.
 #[derive(Debug, derive_builder::Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+#[non_exhaustive]
 pub struct AssetCacheConfig {
-    /// Maximum cost of the bytes cache in bytes.
+    /// Maximum cost of the bytes cache in bytes.  Ignored if there is a [AssetCacheConfig::unified_budget].
     pub max_bytes_cost: u64,
-    /// Maximum cost of the decoded cache in bytes.
+    /// Maximum cost of the decoded cache in bytes.  Ignored if there is a [AssetCacheConfig::unified_budget].
     pub max_decoded_cost: u64,
     /// Maximum size of a single vec of bytes before we won't cache it.
     ///
@@ -38,22 +93,323 @@ pub struct AssetCacheConfig {
     /// Note that even when we choose not to cache such objects, we still keep them around via weak references, so it's
     /// not always the case that the cache will refuse to give it back to you without decoding a second time.
     pub max_single_object_decoded_cost: u64,
+    /// Whether to compress entries in the bytes cache, which are then charged at their compressed size.
+    ///
+    /// The single object limit still applies to the uncompressed size.  Buffers from readers which provide
+    /// [VfsReader::mapped_bytes] are shared rather than compressed.
+    #[builder(default)]
+    pub bytes_compression: BytesCompression,
+    /// For readers which provide [VfsReader::mapped_bytes], such as [FilesystemVfs] in mmap mode, the percentage of the
+    /// mapped length charged against the bytes cache.  Defaults to 100.
+    ///
+    /// Mapped pages are only loaded as they're touched and can be dropped by the OS under memory pressure, so it can
+    /// make sense to charge them less than their size.  The single object limit applies to the charged cost.
+    #[builder(default = "100")]
+    pub mapped_bytes_cost_percent: u64,
+    /// Replace the separate bytes and decoded budgets with one budget for both.
+    ///
+    /// The single object limits still apply.
+    #[builder(default, setter(strip_option))]
+    pub unified_budget: Option<UnifiedBudget>,
+    /// Once a tier goes over its maximum cost, evict down to this percentage of it rather than just under it.
+    ///
+    /// Defaults to 100.  See [CostBasedLru::with_watermarks].
+    #[builder(default = "100")]
+    pub low_watermark_percent: u64,
+    /// Whether the cost of items which are only alive because something outside the cache holds them counts against
+    /// the decoded budget (or the unified budget, if there is one).
+    ///
+    /// See [AssetCache::unmanaged_cost].  Checking it is linear in the number of items the cache has handed out, which
+    /// is then done on every insert.
+    #[builder(default)]
+    pub count_unmanaged_cost: bool,
+    /// Maximum total cost of pinned items, past which [AssetCache::cache_always] and [AssetCache::pin]
+    /// fail.  Unlimited by default.
+    ///
+    /// Pinned items are never evicted, so they don't count against any other budget.
+    #[builder(default, setter(strip_option))]
+    pub max_pinned_cost: Option<u64>,
+    /// Whether to check items which were already decoded against the current [VfsReader::content_hash] of their
+    /// source, and decode again if it changed.
+    ///
+    /// Items which changed are replaced as if by [AssetCache::reload], so pinned items stay pinned, [AssetHandle]s see
+    /// the new version, and invalidation listeners are told.  This opens the source on every lookup of an item decoded
+    /// from a reader with a hash, so it is off by default.  The bytes tier and disk tier always check hashes, since
+    /// they open the source anyway.  Readers without a hash are assumed current.
+    #[builder(default)]
+    pub validate_content_hash: bool,
+}
+
+impl AssetCacheConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        // With a unified budget the tiers have no limits of their own.
+        if matches!(self.unified_budget, Some(Some(_))) {
+            return Ok(());
+        }
+
+        let percent = self.low_watermark_percent.unwrap_or(100).min(100);
+        let check = |name: &str, max: Option<u64>, single: Option<u64>| match (max, single) {
+            (Some(max), Some(single)) if single > low_watermark(max, percent) => Err(format!(
+                "max_single_object_{}_cost ({}) is above the low watermark of the {} tier ({})",
+                name,
+                single,
+                name,
+                low_watermark(max, percent)
+            )),
+            _ => Ok(()),
+        };
+        check(
+            "bytes",
+            self.max_bytes_cost,
+            self.max_single_object_bytes_cost,
+        )?;
+        check(
+            "decoded",
+            self.max_decoded_cost,
+            self.max_single_object_decoded_cost,
+        )
+    }
+}
+
+fn low_watermark(max: u64, percent: u64) -> u64 {
+    (max as u128 * percent as u128 / 100) as u64
+}
+
+/// One of the tiers of an [AssetCache], for [AssetCache::trim].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Tier {
+    /// The bytes read from the [Vfs].
+    Bytes,
+    /// The decoded outputs.
+    Decoded,
+}
+
+/// Which parts of an [AssetCache] bulk removals such as [AssetCache::remove_prefix] touch.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RemovalScope {
+    /// The bytes tier.
+    pub bytes: bool,
+    /// The decoded tier.
+    pub decoded: bool,
+    /// Items the cache can still hand back because something outside it holds them.
+    pub weak_refs: bool,
+    /// Pinned items.  Outstanding [PinGuard]s keep their items alive, but no longer pin anything.
+    pub pinned: bool,
+}
+
+impl RemovalScope {
+    /// Everything, like [AssetCache::remove].
+    pub const ALL: RemovalScope = RemovalScope {
+        bytes: true,
+        decoded: true,
+        weak_refs: true,
+        pinned: true,
+    };
+
+    /// Only the tiers subject to eviction, so the next load reads and decodes again unless something still holds the
+    /// item.
+    pub const CACHED: RemovalScope = RemovalScope {
+        bytes: true,
+        decoded: true,
+        weak_refs: false,
+        pinned: false,
+    };
+}
+
+/// A pinned item and the [PinnedEntry::id] of the entry pinning it, from [AssetCache::pin_normalized].
+pub(crate) type PinResult<T, E> = Result<(Arc<T>, u64), AssetCacheError<E>>;
+
+/// Source of [PinnedEntry::id].
+static NEXT_PIN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An item pinned with [AssetCache::cache_always] or [AssetCache::pin].
+struct PinnedEntry<T> {
+    item: Arc<T>,
+    cost: u64,
+    /// Distinguishes this entry from earlier ones for the same key, so that pins taken on an entry which has since been
+    /// removed can't release a newer one.
+    id: u64,
+    /// Pinned with [AssetCache::cache_always], until [AssetCache::unpin].
+    always: bool,
+    /// Number of live [PinGuard]s.
+    guards: usize,
+}
+
+/// A weak reference to an item the cache handed out, so that we can hand it out again while it is alive.
+struct WeakEntry<T> {
+    item: std::sync::Weak<T>,
+    cost: u64,
+    /// The [VfsReader::content_hash] of what the item was decoded from, if known.
+    content_hash: Option<u64>,
+}
+
+/// What [AssetCache::search_for_current_item] found.
+enum Lookup<T> {
+    Current(Arc<T>),
+    /// There is an item, but it was decoded from content which has since changed.
+    Stale,
+    Missing,
+}
+
+/// Whether something recorded with one content hash is current for another.  Unknown hashes match anything.
+fn content_hashes_match(recorded: Option<u64>, current: Option<u64>) -> bool {
+    match (recorded, current) {
+        (Some(r), Some(c)) => r == c,
+        _ => true,
+    }
+}
+
+/// Counts bytes read through a reader which is handed to the decoder.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a mut u64,
+}
+
+impl<'a, R: Read> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let got = self.inner.read(buf)?;
+        *self.count += got as u64;
+        Ok(got)
+    }
+}
+
+impl<'a, R: std::io::Seek> std::io::Seek for CountingReader<'a, R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// The tiers of an [AssetCache] which are subject to eviction.
+///
+/// These are behind an `Arc` so that a [CacheGroup] can evict from them without knowing the cache's types.
+pub(crate) struct Tiers<K: CacheKey + ?Sized, Output> {
+    pub(crate) bytes: Mutex<CostBasedLru<K::Source, BytesEntry>>,
+    pub(crate) decoded: Mutex<CostBasedLru<K, Output>>,
+}
+
+impl<K: CacheKey + ?Sized, Output> Tiers<K, Output> {
+    pub(crate) fn trim(&self, tier: Tier, target_cost: u64) {
+        match tier {
+            Tier::Bytes => self.bytes.lock().unwrap().trim(target_cost),
+            Tier::Decoded => self.decoded.lock().unwrap().trim(target_cost),
+        }
+    }
+
+    pub(crate) fn shrink_to(&self, fraction: f64) {
+        let fraction = fraction.clamp(0.0, 1.0);
+        for tier in [Tier::Bytes, Tier::Decoded] {
+            let current = match tier {
+                Tier::Bytes => self.bytes.lock().unwrap().current_cost(),
+                Tier::Decoded => self.decoded.lock().unwrap().current_cost(),
+            };
+            self.trim(tier, (current as f64 * fraction) as u64);
+        }
+    }
 }
 
 /// The Asset cache itself.  See crate level documentation for details.
-pub struct AssetCache<VfsImpl: Vfs, DecoderImpl: Decoder> {
+pub struct AssetCache<VfsImpl, DecoderImpl, K = str>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
     config: AssetCacheConfig,
-    pinned_entries: RwLock<CacheHashMap<Arc<DecoderImpl::Output>>>,
-    bytes_cache: Mutex<CostBasedLru<str, Vec<u8>>>,
-    decoded_cache: Mutex<CostBasedLru<str, DecoderImpl::Output>>,
+    pinned_entries: RwLock<CacheHashMap<K, PinnedEntry<DecoderImpl::Output>>>,
+    tiers: Arc<Tiers<K, DecoderImpl::Output>>,
     /// Mutexes that stop multiple threads trying to decode the same content.
-    decoding_guards: Mutex<CacheHashMap<Arc<Mutex<()>>>>,
+    decoding_guards: Mutex<CacheHashMap<K, Arc<Mutex<()>>>>,
     /// After eviction, we can still give the item back if something external kept it around; do so unless the user explicitly deleted it.
-    weak_refs: RwLock<CacheHashMap<std::sync::Weak<DecoderImpl::Output>>>,
+    weak_refs: RwLock<CacheHashMap<K, WeakEntry<DecoderImpl::Output>>>,
+    pub(crate) key_normalizer: Option<Box<dyn KeyNormalizer>>,
+    disk_tier: Option<DiskTier<DecoderImpl::Output>>,
+    group: Option<CacheGroup>,
+    tags: RwLock<TagIndex<K>>,
+    /// For each tag pinned with [AssetCache::pin_tag], the keys pinned on its behalf and the ids of their pins.
+    ///
+    /// Never held while decoding, so that loading one tag doesn't hold up every other tagged lookup.
+    pinned_tags: Mutex<TagHashMap<CacheHashMap<K, u64>>>,
+    pub(crate) working_set: Mutex<WorkingSet<K>>,
+    /// Slots shared by the outstanding [AssetHandle]s for each key.
+    handles: Mutex<CacheHashMap<K, std::sync::Weak<HandleSlot<DecoderImpl::Output>>>>,
+    fallback_value: Option<Arc<DecoderImpl::Output>>,
+    fallback_resolver: Option<Box<FallbackResolver<K, DecoderImpl::Error>>>,
+    pub(crate) invalidation_listeners: Mutex<Vec<std::sync::Weak<dyn InvalidationListener<K>>>>,
     vfs: VfsImpl,
     decoder: DecoderImpl,
 }
 
+/// Keeps an item pinned in an [AssetCache] while alive.  Returned by [AssetCache::pin].
+///
+/// Dereferences to the item.
+pub struct PinGuard<'a, VfsImpl, DecoderImpl, K = str>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    cache: &'a AssetCache<VfsImpl, DecoderImpl, K>,
+    key: Arc<K>,
+    pin_id: u64,
+    item: Arc<DecoderImpl::Output>,
+}
+
+impl<'a, VfsImpl, DecoderImpl, K> PinGuard<'a, VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    /// The normalized key of the pinned item.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Get a reference to the item which outlives the guard.
+    pub fn item(&self) -> Arc<DecoderImpl::Output> {
+        self.item.clone()
+    }
+}
+
+impl<'a, VfsImpl, DecoderImpl, K> std::ops::Deref for PinGuard<'a, VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    type Target = DecoderImpl::Output;
+
+    fn deref(&self) -> &DecoderImpl::Output {
+        &self.item
+    }
+}
+
+impl<'a, VfsImpl, DecoderImpl, K> Drop for PinGuard<'a, VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    fn drop(&mut self) {
+        self.cache.release_pin(&self.key, self.pin_id);
+    }
+}
+
+/// An item from [AssetCache::get_or_fallback].
+#[derive(Debug)]
+pub struct WithFallback<Output, DecoderError> {
+    /// The item, or the fallback if loading it failed.
+    pub item: Arc<Output>,
+    /// Why loading the item failed, if `item` is a fallback.
+    pub error: Option<AssetCacheError<DecoderError>>,
+}
+
+impl<Output, DecoderError> WithFallback<Output, DecoderError> {
+    pub fn is_fallback(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
 /// An error from attempting to decode via the asset cache.
 #[derive(Debug, thiserror::Error)]
 pub enum AssetCacheError<DecoderError> {
@@ -63,37 +419,156 @@ pub enum AssetCacheError<DecoderError> {
     /// The error comes from the [Decoder].
     #[error("Decoder error reading from cache")]
     Decoder(#[source] DecoderError),
+    /// Pinning an item would take the pinned items over [AssetCacheConfig::max_pinned_cost].
+    #[error("Pinning an item of cost {cost} would exceed the pinned budget of {max_pinned_cost}")]
+    PinnedBudgetExceeded { cost: u64, max_pinned_cost: u64 },
 }
 
-impl<VfsImpl: Vfs, DecoderImpl: Decoder> AssetCache<VfsImpl, DecoderImpl> {
+impl<VfsImpl, DecoderImpl, K> AssetCache<VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
     pub fn new(
         vfs: VfsImpl,
         decoder: DecoderImpl,
         config: AssetCacheConfig,
-    ) -> AssetCache<VfsImpl, DecoderImpl> {
+    ) -> AssetCache<VfsImpl, DecoderImpl, K> {
+        // With a unified budget, the tiers don't evict by themselves; see enforce_unified_budget.
+        let (max_bytes_cost, max_decoded_cost) = match config.unified_budget {
+            Some(_) => (u64::MAX, u64::MAX),
+            None => (config.max_bytes_cost, config.max_decoded_cost),
+        };
+        let low = |max: u64| low_watermark(max, config.low_watermark_percent.min(100));
         AssetCache {
             decoder,
             vfs,
-            bytes_cache: Mutex::new(CostBasedLru::new(config.max_bytes_cost)),
-            decoded_cache: Mutex::new(CostBasedLru::new(config.max_decoded_cost)),
+            tiers: Arc::new(Tiers {
+                bytes: Mutex::new(CostBasedLru::with_watermarks(
+                    max_bytes_cost,
+                    low(max_bytes_cost),
+                )),
+                decoded: Mutex::new(CostBasedLru::with_watermarks(
+                    max_decoded_cost,
+                    low(max_decoded_cost),
+                )),
+            }),
             decoding_guards: Default::default(),
             pinned_entries: RwLock::new(Default::default()),
             weak_refs: RwLock::new(Default::default()),
+            key_normalizer: None,
+            disk_tier: None,
+            group: None,
+            tags: Default::default(),
+            pinned_tags: Default::default(),
+            working_set: Default::default(),
+            handles: Default::default(),
+            fallback_value: None,
+            fallback_resolver: None,
+            invalidation_listeners: Default::default(),
             config,
         }
     }
 
+    /// Apply a [KeyNormalizer] to every key before it is looked up or passed to the [Vfs], for example a
+    /// [DefaultKeyNormalizer] so that different spellings of a path share one entry.
+    ///
+    /// By default keys are used as given.  Only `str` keys and the paths of [ParamKey]s are normalized.
+    pub fn with_key_normalizer(
+        mut self,
+        normalizer: impl KeyNormalizer,
+    ) -> AssetCache<VfsImpl, DecoderImpl, K> {
+        self.key_normalizer = Some(Box::new(normalizer));
+        self
+    }
+
+    /// Set a value for [AssetCache::get_or_fallback] to return when a key fails to load and there is no fallback key.
+    pub fn with_fallback_value(
+        mut self,
+        value: Arc<DecoderImpl::Output>,
+    ) -> AssetCache<VfsImpl, DecoderImpl, K> {
+        self.fallback_value = Some(value);
+        self
+    }
+
+    /// Set a function which, given a key which failed to load and the error, picks another key for
+    /// [AssetCache::get_or_fallback] to load instead, for example a placeholder texture for a missing one.
+    ///
+    /// Fallback keys are loaded and cached as usual, but don't fall back any further.  If the resolver returns `None`,
+    /// or the fallback key fails too, the value from [AssetCache::with_fallback_value] is used if there is one.
+    pub fn with_fallback_resolver(
+        mut self,
+        resolver: impl Fn(&K, &AssetCacheError<DecoderImpl::Error>) -> Option<K::Owned>
+            + Send
+            + Sync
+            + 'static,
+    ) -> AssetCache<VfsImpl, DecoderImpl, K> {
+        self.fallback_resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Add a persistent tier on disk under the decoded tier, so that decoded outputs survive process restarts.
+    ///
+    /// Only assets for which the [Vfs] reports a [VfsMetadata::version] are persisted, and only for keys with a string
+    /// form (see [CacheKey::as_str_key]).  See [DiskTierConfig].
+    pub fn with_disk_tier(
+        mut self,
+        config: DiskTierConfig,
+    ) -> Result<AssetCache<VfsImpl, DecoderImpl, K>, IoError>
+    where
+        DecoderImpl::Output: PersistentOutput,
+    {
+        self.disk_tier = Some(DiskTier::open(config)?);
+        Ok(self)
+    }
+
+    /// Join a [CacheGroup], which then evicts from this cache as part of keeping all its members under one budget.
+    ///
+    /// This cache's own budgets still apply.
+    pub fn with_group(mut self, group: &CacheGroup) -> AssetCache<VfsImpl, DecoderImpl, K>
+    where
+        DecoderImpl::Output: 'static,
+    {
+        let tiers: Arc<dyn GroupMember> = self.tiers.clone();
+        group.register(Arc::downgrade(&tiers));
+        self.group = Some(group.clone());
+        self
+    }
+
+    /// Register with a [MemoryMonitor], which then shrinks this cache when memory runs low.
+    pub fn with_memory_monitor(self, monitor: &MemoryMonitor) -> AssetCache<VfsImpl, DecoderImpl, K>
+    where
+        DecoderImpl::Output: 'static,
+    {
+        let tiers: Arc<dyn ShrinkTarget> = self.tiers.clone();
+        monitor.register(Arc::downgrade(&tiers));
+        self
+    }
+
+    /// Apply the [KeyNormalizer], if any, to a key, borrowing it if it is already normal.
+    pub(crate) fn normalize<'k>(&self, key: &'k K) -> NormalizedKey<'k, K> {
+        match self
+            .key_normalizer
+            .as_ref()
+            .and_then(|n| key.normalize_with(&**n))
+        {
+            Some(k) => NormalizedKey::Owned(k),
+            None => NormalizedKey::Borrowed(key),
+        }
+    }
+
     /// Find an item in the cache, returning `None` if it isn't currently cached.
-    fn search_for_item(&self, key: &str) -> Option<Arc<DecoderImpl::Output>> {
+    fn search_for_item(&self, key: &K) -> Option<Arc<DecoderImpl::Output>> {
         {
             let guard = self.pinned_entries.read().unwrap();
             if let Some(x) = guard.get(key) {
-                return Some((*x).clone());
+                return Some(x.item.clone());
             }
         }
 
         {
-            let mut guard = self.decoded_cache.lock().unwrap();
+            let mut guard = self.tiers.decoded.lock().unwrap();
             if let Some(x) = guard.get(key) {
                 return Some(x);
             }
@@ -104,7 +579,41 @@ impl<VfsImpl: Vfs, DecoderImpl: Decoder> AssetCache<VfsImpl, DecoderImpl> {
             .read()
             .unwrap()
             .get(key)
-            .and_then(|x| x.upgrade())
+            .and_then(|x| x.item.upgrade())
+    }
+
+    /// Like [AssetCache::search_for_item], but if [AssetCacheConfig::validate_content_hash] is set, check items,
+    /// pinned or not, against the content hash of their source.
+    ///
+    /// Stale items are left where they are, for the caller to replace once it has decoded the new version.
+    fn search_for_current_item(&self, key: &K) -> Lookup<DecoderImpl::Output> {
+        let found = match self.search_for_item(key) {
+            Some(x) => x,
+            None => return Lookup::Missing,
+        };
+        if !self.config.validate_content_hash {
+            return Lookup::Current(found);
+        }
+
+        let recorded = self
+            .weak_refs
+            .read()
+            .unwrap()
+            .get(key)
+            .and_then(|w| w.content_hash);
+        if recorded.is_none() {
+            return Lookup::Current(found);
+        }
+        // If we can't open it, decoding again would fail anyway; let the caller have what we've got.
+        let current = match self.vfs.open(key.source()) {
+            Ok(r) => r.content_hash(),
+            Err(_) => return Lookup::Current(found),
+        };
+        if content_hashes_match(recorded, current) {
+            Lookup::Current(found)
+        } else {
+            Lookup::Stale
+        }
     }
 
     /// Decode an item for the cache, assuming we definitely know it isn't present and are holding the guard necessary
@@ -113,165 +622,1054 @@ impl<VfsImpl: Vfs, DecoderImpl: Decoder> AssetCache<VfsImpl, DecoderImpl> {
     /// This is hard to break up into smaller functions, unfortunately.
     fn find_or_decode_postchecked(
         &self,
-        key: &str,
+        key: &K,
+        bytes_read: &mut u64,
     ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
-        // First, if we can find the item, return it immediately.
-        if let Some(x) = self.search_for_item(key) {
-            return Ok(x);
+        // First, if we can find the item, return it immediately.  If what we found is stale, the new version replaces
+        // it as if reloaded.
+        let stale = match self.search_for_current_item(key) {
+            Lookup::Current(x) => return Ok(x),
+            Lookup::Stale => true,
+            Lookup::Missing => false,
+        };
+
+        // Next, try the disk tier.  This needs a version or a content hash.  We get the version before opening so that
+        // a change in between can't get the old content persisted under the new version.
+        let disk_target = self
+            .disk_tier
+            .as_ref()
+            .and_then(|tier| Some((tier, key.as_str_key()?)));
+        let vfs_version = disk_target
+            .and_then(|_| self.vfs.metadata(key.source()).ok())
+            .and_then(|m| m.version);
+        let source = key.source();
+        let mut bytes_reader = self.vfs.open(source).map_err(AssetCacheError::Vfs)?;
+        let content_hash = bytes_reader.content_hash();
+        let disk_target = disk_target
+            .map(|(tier, str_key)| {
+                let stamp = DiskStamp {
+                    vfs_version,
+                    content_hash,
+                    decoder_version: self.decoder.version(),
+                };
+                (tier, str_key, stamp)
+            })
+            .filter(|(_, _, stamp)| stamp.identifies_content());
+        if let Some((tier, str_key, stamp)) = &disk_target {
+            if let Some(decoded) = tier.load(str_key, stamp) {
+                return self.insert_loaded(key, decoded, content_hash, stale);
+            }
         }
 
         // If we can get the size of the item, and it is less than the single object limit, we cache a vec of bytes.
-        // Otherwise, we feed the reader into the decoder directly.
-
-        let mut bytes_reader = self.vfs.open(key).map_err(AssetCacheError::Vfs)?;
-        let size = bytes_reader.get_size().map_err(AssetCacheError::Vfs)?;
-        let decoded = if size <= self.config.max_single_object_bytes_cost {
-            let maybe_cached_bytes = self.bytes_cache.lock().unwrap().get(key);
-            if let Some(x) = maybe_cached_bytes {
-                self.decoder
-                    .decode_bytes(&x[..])
-                    .map_err(AssetCacheError::Decoder)?
+        // Readers which already have the content mapped give it to us instead, and we cache that.  Otherwise, we feed
+        // the reader into the decoder directly.  Bytes are cached by the key's source, so keys which differ only in
+        // their parameters share them.  Cached bytes recorded with a different content hash are stale, and are
+        // replaced.
+        let mapped = bytes_reader.mapped_bytes();
+        let cacheable_size = match &mapped {
+            Some(m) => Some(mapped_cost(
+                m.len() as u64,
+                self.config.mapped_bytes_cost_percent,
+            )),
+            // Readers which can't tell us are decoded from directly.
+            None => match bytes_reader.get_size() {
+                Ok(s) => Some(s),
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => None,
+                Err(e) => return Err(AssetCacheError::Vfs(e)),
+            },
+        }
+        .filter(|s| *s <= self.config.max_single_object_bytes_cost);
+        let decoded = if cacheable_size.is_some() {
+            let maybe_cached_bytes = self
+                .tiers
+                .bytes
+                .lock()
+                .unwrap()
+                .get(source)
+                .filter(|b| content_hashes_match(b.content_hash(), content_hash));
+            let bytes = if let Some(x) = maybe_cached_bytes {
+                x
             } else {
-                // Read to a vec, insert that vec, then read from the vec.
-                let mut dest = vec![];
-                bytes_reader
-                    .read_to_end(&mut dest)
-                    .map_err(AssetCacheError::Vfs)?;
-                let will_use = {
-                    let mut guard = self.bytes_cache.lock().unwrap();
-                    guard.insert(key.to_string().into(), dest, size);
-                    guard.get(key).expect("We just inserted this")
+                let (entry, size) = if let Some(m) = mapped {
+                    *bytes_read += m.len() as u64;
+                    let size = mapped_cost(m.len() as u64, self.config.mapped_bytes_cost_percent);
+                    (
+                        BytesEntry::mapped(m, self.config.mapped_bytes_cost_percent),
+                        size,
+                    )
+                } else {
+                    // Read to a vec, insert that vec, then read from the vec.
+                    let mut dest = vec![];
+                    bytes_reader
+                        .read_to_end(&mut dest)
+                        .map_err(AssetCacheError::Vfs)?;
+                    *bytes_read += dest.len() as u64;
+                    let size = dest.len() as u64;
+                    (BytesEntry::new(dest, self.config.bytes_compression), size)
                 };
-                self.decoder
-                    .decode_bytes(&will_use[..])
-                    .map_err(AssetCacheError::Decoder)?
-            }
+                let entry = Arc::new(entry.with_content_hash(content_hash));
+                // The reader's size is only a hint, so what we actually got decides whether we keep it.
+                if size <= self.config.max_single_object_bytes_cost {
+                    self.tiers.bytes.lock().unwrap().insert_arc(
+                        source.to_shared(),
+                        entry.clone(),
+                        entry.cost(),
+                    );
+                    self.enforce_shared_budgets();
+                }
+                entry
+            };
+            bytes
+                .decode(&self.decoder, key.params())
+                .map_err(AssetCacheError::Decoder)?
+        } else if let Some(m) = mapped {
+            // Too big to keep, but there's no reason to copy it either.
+            *bytes_read += m.len() as u64;
+            self.decoder
+                .decode_shared_with(m, key.params())
+                .map_err(AssetCacheError::Decoder)?
         } else {
             // The object was too big, or we couldn't get the size; in this case, we feed the vfs directly to the
             // decoder.
             self.decoder
-                .decode(bytes_reader)
+                .decode_with(
+                    CountingReader {
+                        inner: bytes_reader,
+                        count: bytes_read,
+                    },
+                    key.params(),
+                )
                 .map_err(AssetCacheError::Decoder)?
         };
 
+        if let Some((tier, str_key, stamp)) = &disk_target {
+            // The disk tier is only an optimization, so failing to write to it shouldn't fail the load.
+            let _ = tier.store(str_key, stamp, &decoded);
+        }
+
+        self.insert_loaded(key, decoded, content_hash, stale)
+    }
+
+    /// Insert an item loaded from the [Vfs] or disk tier, replacing a stale version if there was one.
+    fn insert_loaded(
+        &self,
+        key: &K,
+        decoded: DecoderImpl::Output,
+        content_hash: Option<u64>,
+        stale: bool,
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        if stale {
+            self.publish_replacement(key, decoded, content_hash)
+        } else {
+            self.insert_decoded(key, decoded, content_hash)
+        }
+    }
+
+    /// Put a freshly decoded item into the decoded tier if it fits, and the weak references regardless.
+    fn insert_decoded(
+        &self,
+        key: &K,
+        decoded: DecoderImpl::Output,
+        content_hash: Option<u64>,
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
         let cost = self
             .decoder
             .estimate_cost(&decoded)
             .map_err(AssetCacheError::Decoder)?;
         let res = if cost <= self.config.max_single_object_decoded_cost {
-            let mut guard = self.decoded_cache.lock().unwrap();
-            guard.insert(key.to_string().into(), decoded, cost);
-            guard.get(key).expect("Just inserted")
+            let res = Arc::new(decoded);
+            self.tiers
+                .decoded
+                .lock()
+                .unwrap()
+                .insert_arc(key.to_shared(), res.clone(), cost);
+            res
         } else {
             Arc::new(decoded)
         };
 
-        let weak = Arc::downgrade(&res);
+        let weak = WeakEntry {
+            item: Arc::downgrade(&res),
+            cost,
+            content_hash,
+        };
         self.weak_refs
             .write()
             .unwrap()
-            .insert(key.to_string(), weak);
+            .insert(key.to_shared(), weak);
+        self.enforce_shared_budgets();
         Ok(res)
     }
 
-    /// Find or decode an item from the cache.
-    fn find_or_decode(
+    /// Enforce the budgets which span more than one tier: the unified budget, then the group's.
+    ///
+    /// Must be called without holding either tier's lock.
+    fn enforce_shared_budgets(&self) {
+        let unmanaged = if self.config.count_unmanaged_cost {
+            self.unmanaged_cost()
+        } else {
+            0
+        };
+
+        if self.config.unified_budget.is_some() {
+            self.enforce_unified_budget(unmanaged);
+        } else if unmanaged > 0 {
+            self.tiers
+                .decoded
+                .lock()
+                .unwrap()
+                .trim(self.config.max_decoded_cost.saturating_sub(unmanaged));
+        }
+
+        if let Some(g) = &self.group {
+            g.enforce();
+        }
+    }
+
+    /// If there is a unified budget, evict from the tiers until they, plus `unmanaged` cost, are back under it.
+    fn enforce_unified_budget(&self, unmanaged: u64) {
+        let budget = match &self.config.unified_budget {
+            Some(b) => b,
+            None => return,
+        };
+
+        let mut bytes = self.tiers.bytes.lock().unwrap();
+        let mut decoded = self.tiers.decoded.lock().unwrap();
+        while bytes.current_cost() + decoded.current_cost() + unmanaged > budget.total_cost {
+            let now = recency_now();
+            let weighted_age = |stamp: Option<u64>, cost: u64, floor: u64, priority: u64| {
+                let stamp = stamp.filter(|_| cost > floor)?;
+                // Compare age / priority without dividing, by scaling by the other tier's priority.
+                Some((now - stamp) as u128 * priority as u128)
+            };
+            let bytes_age = weighted_age(
+                bytes.lru_stamp(),
+                bytes.current_cost(),
+                budget.bytes_floor,
+                budget.decoded_priority,
+            );
+            let decoded_age = weighted_age(
+                decoded.lru_stamp(),
+                decoded.current_cost(),
+                budget.decoded_floor,
+                budget.bytes_priority,
+            );
+
+            match (bytes_age, decoded_age) {
+                (None, None) => break,
+                (Some(b), Some(d)) if b < d => {
+                    decoded.pop_lru();
+                }
+                (Some(_), _) => {
+                    bytes.pop_lru();
+                }
+                (None, Some(_)) => {
+                    decoded.pop_lru();
+                }
+            }
+        }
+    }
+
+    /// The mutex which threads decoding or replacing a key hold.
+    fn decoding_guard(&self, key: &K) -> Arc<Mutex<()>> {
+        let mut guard_inner = self.decoding_guards.lock().unwrap();
+        if let Some(x) = guard_inner.get(key) {
+            return x.clone();
+        }
+        let tmp = Arc::new(Mutex::new(()));
+        guard_inner.insert(key.to_shared(), tmp.clone());
+        tmp
+    }
+
+    /// Find or decode an item from the cache, adding the number of bytes read from the [Vfs] to `bytes_read`.
+    pub(crate) fn find_or_decode(
         &self,
-        key: &str,
+        key: &K,
+        bytes_read: &mut u64,
     ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
-        if let Some(x) = self.search_for_item(key) {
+        if let Lookup::Current(x) = self.search_for_current_item(key) {
             return Ok(x);
         }
 
         // Stop any other threads from trying to decode this item, and make them wait on this thread to finish.
-        let mutex = {
-            let mut guard_inner = self.decoding_guards.lock().unwrap();
-            let tmp = guard_inner
-                .entry(key.to_string())
-                .or_insert_with(|| Arc::new(Mutex::new(())));
-            (*tmp).clone()
-        };
+        let mutex = self.decoding_guard(key);
         // The type here is important: it makes sure that we actually lock the mutex, by making this variable definitely
         // be a guard.  Any mistakes in the above rather complicated chain to set this up will be caught at compile
         // time.
         let _guard: std::sync::MutexGuard<()> = mutex.lock().unwrap();
 
-        self.find_or_decode_postchecked(key)
+        self.find_or_decode_postchecked(key, bytes_read)
     }
 
     /// Get an item from the cache, decoding if the item isn't present.
     pub fn get(
         &self,
-        key: &str,
+        key: &K,
     ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
-        self.find_or_decode(key)
+        self.find_or_decode(&self.normalize(key), &mut 0)
     }
 
-    /// Pin an item, so that it is always present in the cache until explicitly removed.
-    pub fn cache_always(&self, key: String, value: Arc<DecoderImpl::Output>) {
-        let weak = Arc::downgrade(&value);
-        self.pinned_entries
-            .write()
+    /// Like [AssetCache::get], but return an [AssetHandle] which follows the item across [AssetCache::replace] and
+    /// [AssetCache::reload].
+    ///
+    /// All live handles for a key share one slot, so they always agree on the current version.
+    pub fn get_handle(
+        &self,
+        key: &K,
+    ) -> Result<AssetHandle<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        let key = self.normalize(key);
+        if let Some(slot) = self
+            .handles
+            .lock()
             .unwrap()
-            .insert(key.clone(), value);
-        self.weak_refs.write().unwrap().insert(key, weak);
+            .get(&*key)
+            .and_then(|w| w.upgrade())
+        {
+            return Ok(AssetHandle::new(slot));
+        }
+
+        let item = self.find_or_decode(&key, &mut 0)?;
+        let mut handles = self.handles.lock().unwrap();
+        // Someone may have made a slot while we were loading.
+        if let Some(slot) = handles.get(&*key).and_then(|w| w.upgrade()) {
+            return Ok(AssetHandle::new(slot));
+        }
+        handles.retain(|_, w| w.strong_count() > 0);
+        let slot = Arc::new(HandleSlot::new(item));
+        handles.insert(key.to_shared(), Arc::downgrade(&slot));
+        Ok(AssetHandle::new(slot))
     }
 
-    /// Remove an item from the cache.
-    pub fn remove(&self, key: &str) {
-        self.pinned_entries.write().unwrap().remove(key);
-        self.bytes_cache.lock().unwrap().remove(key);
-        self.decoding_guards.lock().unwrap().remove(key);
-        self.decoded_cache.lock().unwrap().remove(key);
-        self.weak_refs.write().unwrap().remove(key);
+    /// Replace the item under a key with a new value, and publish it to every [AssetHandle] for the key.
+    ///
+    /// The value takes the place of the old one wherever it was: if the key is pinned, it stays pinned with the new
+    /// value, and otherwise it goes into the decoded tier as if it had just been decoded.  Cached bytes for the key's
+    /// source are dropped, since they no longer match.  `Arc`s to the old value from [AssetCache::get] are unaffected.
+    pub fn replace(
+        &self,
+        key: &K,
+        value: DecoderImpl::Output,
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        let key = self.normalize(key);
+        let mutex = self.decoding_guard(&key);
+        let _guard: std::sync::MutexGuard<()> = mutex.lock().unwrap();
+        self.replace_locked(&key, value, None)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+    /// Decode an item again from the [Vfs], bypassing every tier, and [AssetCache::replace] it with the result.
+    ///
+    /// Use this to pick up changes to files on disk.
+    pub fn reload(
+        &self,
+        key: &K,
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        let key = self.normalize(key);
+        let mutex = self.decoding_guard(&key);
+        let _guard: std::sync::MutexGuard<()> = mutex.lock().unwrap();
+        let reader = self.vfs.open(key.source()).map_err(AssetCacheError::Vfs)?;
+        let content_hash = reader.content_hash();
+        let decoded = self
+            .decoder
+            .decode_with(reader, key.params())
+            .map_err(AssetCacheError::Decoder)?;
+        self.replace_locked(&key, decoded, content_hash)
+    }
 
-    use super::*;
+    /// Replace an item, with the key's decoding guard held.
+    fn replace_locked(
+        &self,
+        key: &K,
+        value: DecoderImpl::Output,
+        content_hash: Option<u64>,
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        self.tiers.bytes.lock().unwrap().remove(key.source());
+        self.publish_replacement(key, value, content_hash)
+    }
 
-    /// A VFS wrapping a `HashMap` for testing.
-    struct HashMapVfs(Mutex<HashMap<String, Vec<u8>>>);
+    /// Put a new version of an item wherever the old one was, publish it to handles and tell invalidation listeners,
+    /// with the key's decoding guard held.  Cached bytes are left alone.
+    fn publish_replacement(
+        &self,
+        key: &K,
+        value: DecoderImpl::Output,
+        content_hash: Option<u64>,
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        let cost = self
+            .decoder
+            .estimate_cost(&value)
+            .map_err(AssetCacheError::Decoder)?;
 
-    impl Vfs for HashMapVfs {
-        type Reader = std::io::Cursor<Vec<u8>>;
+        // Pinned items are updated in place; otherwise we get the value back to insert.
+        let pinned = {
+            let mut pinned = self.pinned_entries.write().unwrap();
+            match pinned.get_mut(key) {
+                Some(p) => {
+                    p.item = Arc::new(value);
+                    p.cost = cost;
+                    Ok(p.item.clone())
+                }
+                None => Err(value),
+            }
+        };
+        let item = match pinned {
+            Ok(item) => {
+                self.weak_refs.write().unwrap().insert(
+                    key.to_shared(),
+                    WeakEntry {
+                        item: Arc::downgrade(&item),
+                        cost,
+                        content_hash,
+                    },
+                );
+                item
+            }
+            Err(value) => {
+                // Otherwise a value too big for the decoded tier would leave the old one behind.
+                self.tiers.decoded.lock().unwrap().remove(key);
+                self.insert_decoded(key, value, content_hash)?
+            }
+        };
 
-        fn open(&self, key: &str) -> Result<Self::Reader, IoError> {
-            let ret = self
-                .0
-                .lock()
-                .unwrap()
-                .get(key)
-                .ok_or_else(|| {
-                    IoError::new(std::io::ErrorKind::NotFound, "Entry not found".to_string())
-                })?
-                .clone();
-            Ok(std::io::Cursor::new(ret))
+        let slot = self
+            .handles
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|w| w.upgrade());
+        if let Some(slot) = slot {
+            slot.publish(item.clone());
         }
+        self.notify_invalidated(key);
+        Ok(item)
     }
 
-    impl VfsReader for std::io::Cursor<Vec<u8>> {
-        fn get_size(&self) -> Result<u64, IoError> {
-            Ok(self.get_ref().len() as u64)
+    /// Like [AssetCache::get], but if the item fails to load, return a fallback along with the error.
+    ///
+    /// See [AssetCache::with_fallback_resolver] and [AssetCache::with_fallback_value].  The fallback isn't cached under
+    /// the failing key, so the next call tries the key again, and picks up a fix.  Fails with the original error if
+    /// there is no fallback.
+    pub fn get_or_fallback(
+        &self,
+        key: &K,
+    ) -> FallbackResult<DecoderImpl::Output, DecoderImpl::Error> {
+        let key = self.normalize(key);
+        let error = match self.find_or_decode(&key, &mut 0) {
+            Ok(item) => return Ok(WithFallback { item, error: None }),
+            Err(e) => e,
+        };
+
+        let from_key = self
+            .fallback_resolver
+            .as_ref()
+            .and_then(|r| r(&key, &error))
+            .map(|k| match self.normalize(k.borrow()) {
+                NormalizedKey::Owned(normalized) => normalized,
+                NormalizedKey::Borrowed(_) => k,
+            })
+            .filter(|k| k.borrow() != &*key)
+            .and_then(|k| self.find_or_decode(k.borrow(), &mut 0).ok());
+        match from_key.or_else(|| self.fallback_value.clone()) {
+            Some(item) => Ok(WithFallback {
+                item,
+                error: Some(error),
+            }),
+            None => Err(error),
         }
     }
 
-    // Add a helper to put things into the vfs.
-    impl HashMapVfs {
-        fn new() -> HashMapVfs {
-            HashMapVfs(Mutex::new(Default::default()))
-        }
+    /// Pin an item, so that it is always present in the cache until explicitly removed.
+    ///
+    /// The item is charged at [Decoder::estimate_cost] against [AssetCacheConfig::max_pinned_cost].  Pinning over an
+    /// existing pin replaces it.
+    pub fn cache_always(
+        &self,
+        key: &K,
+        value: Arc<DecoderImpl::Output>,
+    ) -> Result<(), AssetCacheError<DecoderImpl::Error>> {
+        let cost = self
+            .decoder
+            .estimate_cost(&value)
+            .map_err(AssetCacheError::Decoder)?;
+        self.cache_always_with_cost(key, value, cost)
+    }
 
-        pub fn insert(&self, key: &str, value: Vec<u8>) -> Option<Vec<u8>> {
-            self.0.lock().unwrap().insert(key.to_string(), value)
+    /// Like [AssetCache::cache_always], but with a cost supplied by the caller.
+    pub fn cache_always_with_cost(
+        &self,
+        key: &K,
+        value: Arc<DecoderImpl::Output>,
+        cost: u64,
+    ) -> Result<(), AssetCacheError<DecoderImpl::Error>> {
+        let key = self.normalize(key).to_shared();
+        let weak = WeakEntry {
+            item: Arc::downgrade(&value),
+            cost,
+            content_hash: None,
+        };
+
+        {
+            let mut pinned = self.pinned_entries.write().unwrap();
+            if let Some(max_pinned_cost) = self.config.max_pinned_cost {
+                let others: u64 = pinned
+                    .iter()
+                    .filter(|(k, _)| **k != key)
+                    .map(|(_, p)| p.cost)
+                    .sum();
+                if others.saturating_add(cost) > max_pinned_cost {
+                    return Err(AssetCacheError::PinnedBudgetExceeded {
+                        cost,
+                        max_pinned_cost,
+                    });
+                }
+            }
+            // Outstanding guards keep counting against the same entry.
+            let (guards, id) = match pinned.get(&key) {
+                Some(p) => (p.guards, p.id),
+                None => (0, NEXT_PIN_ID.fetch_add(1, Ordering::Relaxed)),
+            };
+            pinned.insert(
+                key.clone(),
+                PinnedEntry {
+                    item: value,
+                    cost,
+                    id,
+                    always: true,
+                    guards,
+                },
+            );
         }
+        self.weak_refs.write().unwrap().insert(key, weak);
+        Ok(())
     }
 
-    struct HashMapDecoder;
-
+    /// Load an item through the cache as [AssetCache::get] would, and pin it until the returned guard is dropped.
+    ///
+    /// Pins are counted, so several guards can pin the same item, which then goes back to being managed by the cache
+    /// once the last is dropped and it isn't otherwise pinned.  New pins are charged against
+    /// [AssetCacheConfig::max_pinned_cost] like [AssetCache::cache_always].
+    pub fn pin(
+        &self,
+        key: &K,
+    ) -> Result<PinGuard<'_, VfsImpl, DecoderImpl, K>, AssetCacheError<DecoderImpl::Error>> {
+        let key = self.normalize(key).to_shared();
+        let (item, pin_id) = self.pin_normalized(&key, &mut 0)?;
+        Ok(PinGuard {
+            cache: self,
+            key,
+            pin_id,
+            item,
+        })
+    }
+
+    /// Take a counted pin on an already normalized key, returning the item and the id of the pin entry, which must be
+    /// passed to [AssetCache::release_pin] later.  Loading it adds to `bytes_read` like [AssetCache::find_or_decode].
+    pub(crate) fn pin_normalized(
+        &self,
+        key: &K,
+        bytes_read: &mut u64,
+    ) -> PinResult<DecoderImpl::Output, DecoderImpl::Error> {
+        let item = self.find_or_decode(key, bytes_read)?;
+        let cost = self
+            .decoder
+            .estimate_cost(&item)
+            .map_err(AssetCacheError::Decoder)?;
+
+        let mut pinned = self.pinned_entries.write().unwrap();
+        if let Some(p) = pinned.get_mut(key) {
+            p.guards += 1;
+            return Ok((p.item.clone(), p.id));
+        }
+        if let Some(max_pinned_cost) = self.config.max_pinned_cost {
+            let current: u64 = pinned.values().map(|p| p.cost).sum();
+            if current.saturating_add(cost) > max_pinned_cost {
+                return Err(AssetCacheError::PinnedBudgetExceeded {
+                    cost,
+                    max_pinned_cost,
+                });
+            }
+        }
+        // Pinned items are accounted for separately, so they shouldn't take up room in the decoded tier too.
+        self.tiers.decoded.lock().unwrap().remove(key);
+        let id = NEXT_PIN_ID.fetch_add(1, Ordering::Relaxed);
+        pinned.insert(
+            key.to_shared(),
+            PinnedEntry {
+                item: item.clone(),
+                cost,
+                id,
+                always: false,
+                guards: 1,
+            },
+        );
+        Ok((item, id))
+    }
+
+    /// Remove the pin placed by [AssetCache::cache_always], leaving the item in the cache.
+    ///
+    /// Once no [PinGuard]s for it remain, the item goes back into the decoded tier, where it can be evicted as usual.
+    pub fn unpin(&self, key: &K) {
+        let key = self.normalize(key);
+        let released = {
+            let mut pinned = self.pinned_entries.write().unwrap();
+            match pinned.get_mut(&*key) {
+                Some(p) => {
+                    p.always = false;
+                    self.release_pin_locked(&mut pinned, &key)
+                }
+                None => false,
+            }
+        };
+        if released {
+            self.enforce_shared_budgets();
+        }
+    }
+
+    /// Release a pin taken by [AssetCache::pin_normalized], if the entry it was taken on is still pinned.
+    pub(crate) fn release_pin(&self, key: &K, pin_id: u64) {
+        let released = {
+            let mut pinned = self.pinned_entries.write().unwrap();
+            match pinned.get_mut(key) {
+                Some(p) if p.id == pin_id => {
+                    p.guards = p.guards.saturating_sub(1);
+                    self.release_pin_locked(&mut pinned, key)
+                }
+                // Removed while pinned, and maybe pinned again since.
+                _ => false,
+            }
+        };
+        if released {
+            self.enforce_shared_budgets();
+        }
+    }
+
+    /// If nothing pins an entry any more, move it back into the decoded tier.  Returns whether it did, in which case
+    /// the caller should enforce budgets once the lock is released.
+    fn release_pin_locked(
+        &self,
+        pinned: &mut CacheHashMap<K, PinnedEntry<DecoderImpl::Output>>,
+        key: &K,
+    ) -> bool {
+        match pinned.get(key) {
+            Some(p) if !p.always && p.guards == 0 => {}
+            _ => return false,
+        }
+
+        let (key, entry) = pinned.remove_entry(key).expect("Just checked");
+        if entry.cost <= self.config.max_single_object_decoded_cost {
+            self.tiers
+                .decoded
+                .lock()
+                .unwrap()
+                .insert_arc(key, entry.item, entry.cost);
+        }
+        true
+    }
+
+    /// The keys of all pinned items with their costs, sorted by key.
+    pub fn pinned_entries(&self) -> Vec<(K::Owned, u64)>
+    where
+        K::Owned: Ord,
+    {
+        let mut out = self
+            .pinned_entries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, p)| (K::to_owned_key(k), p.cost))
+            .collect::<Vec<_>>();
+        out.sort_unstable();
+        out
+    }
+
+    /// The total cost of all pinned items.
+    pub fn pinned_cost(&self) -> u64 {
+        self.pinned_entries
+            .read()
+            .unwrap()
+            .values()
+            .map(|p| p.cost)
+            .sum()
+    }
+
+    /// Like [AssetCache::get], also tagging the item.
+    ///
+    /// Tags are kept when the item is evicted, so they apply again when it is next loaded, and are only forgotten by
+    /// [AssetCache::remove].  If any of the tags are pinned, the item is pinned along with them.
+    pub fn get_tagged(
+        &self,
+        key: &K,
+        tags: &[&str],
+    ) -> Result<Arc<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        let key = self.normalize(key).to_shared();
+        self.tags.write().unwrap().add(&key, tags);
+
+        let needs_pin = |pinned_tags: &TagHashMap<CacheHashMap<K, u64>>, tag: &str| {
+            pinned_tags
+                .get(tag)
+                .is_some_and(|keys| !keys.contains_key(&key))
+        };
+        let wanted = {
+            let pinned_tags = self.pinned_tags.lock().unwrap();
+            tags.iter()
+                .filter(|t| needs_pin(&pinned_tags, t))
+                .collect::<Vec<_>>()
+        };
+        for tag in wanted {
+            let (_, pin_id) = self.pin_normalized(&key, &mut 0)?;
+            let mut pinned_tags = self.pinned_tags.lock().unwrap();
+            if needs_pin(&pinned_tags, tag) {
+                pinned_tags
+                    .get_mut(*tag)
+                    .expect("Just checked")
+                    .insert(key.clone(), pin_id);
+            } else {
+                // The tag was unpinned, or this key pinned for it by someone else, while we were loading.
+                drop(pinned_tags);
+                self.release_pin(&key, pin_id);
+            }
+        }
+
+        self.find_or_decode(&key, &mut 0)
+    }
+
+    /// Evict every item with a tag from the bytes and decoded tiers.
+    ///
+    /// Pinned items stay, as do the tags themselves.
+    pub fn evict_tag(&self, tag: &str) {
+        let keys = self.tags.read().unwrap().keys(tag);
+        {
+            let mut bytes = self.tiers.bytes.lock().unwrap();
+            for k in keys.iter() {
+                bytes.remove(k.source());
+            }
+        }
+        let mut decoded = self.tiers.decoded.lock().unwrap();
+        for k in keys.iter() {
+            decoded.remove(&**k);
+        }
+    }
+
+    /// Load and pin every item with a tag, until [AssetCache::unpin_tag].
+    ///
+    /// Items tagged with [AssetCache::get_tagged] while the tag is pinned are pinned too.  Pinning a tag which is
+    /// already pinned does nothing.  If any item fails to load or goes over [AssetCacheConfig::max_pinned_cost], the
+    /// tag is left unpinned.
+    pub fn pin_tag(&self, tag: &str) -> Result<(), AssetCacheError<DecoderImpl::Error>> {
+        let mut pins = CacheHashMap::<K, u64>::default();
+        // Load without holding the lock, then check whether more keys were tagged in the meantime.
+        loop {
+            let missing = {
+                let mut pinned_tags = self.pinned_tags.lock().unwrap();
+                if pinned_tags.contains_key(tag) {
+                    drop(pinned_tags);
+                    self.release_pins(pins);
+                    return Ok(());
+                }
+                let missing = self
+                    .tags
+                    .read()
+                    .unwrap()
+                    .keys(tag)
+                    .into_iter()
+                    .filter(|k| !pins.contains_key(k))
+                    .collect::<Vec<_>>();
+                if missing.is_empty() {
+                    pinned_tags.insert(tag.to_string(), pins);
+                    return Ok(());
+                }
+                missing
+            };
+
+            for k in missing {
+                match self.pin_normalized(&k, &mut 0) {
+                    Ok((_, pin_id)) => {
+                        pins.insert(k, pin_id);
+                    }
+                    Err(e) => {
+                        self.release_pins(pins);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn release_pins(&self, pins: CacheHashMap<K, u64>) {
+        for (k, pin_id) in pins {
+            self.release_pin(&k, pin_id);
+        }
+    }
+
+    /// Release the pins taken by [AssetCache::pin_tag].  Items go back to being managed by the cache unless something
+    /// else pins them.
+    pub fn unpin_tag(&self, tag: &str) {
+        if let Some(pins) = self.pinned_tags.lock().unwrap().remove(tag) {
+            self.release_pins(pins);
+        }
+    }
+
+    /// Every tag with the total cost of its items which are currently in memory, sorted by tag.
+    ///
+    /// An item counts at its pinned cost if pinned, and otherwise at its cost in each tier it is in.  Items in several
+    /// tags count towards each of them.
+    pub fn tag_costs(&self) -> Vec<(String, u64)> {
+        let tags = self.tags.read().unwrap();
+        let pinned = self.pinned_entries.read().unwrap();
+        let bytes = self.tiers.bytes.lock().unwrap();
+        let decoded = self.tiers.decoded.lock().unwrap();
+
+        let mut out = tags
+            .tags()
+            .map(|tag| {
+                let cost = tags
+                    .keys(tag)
+                    .iter()
+                    .map(|k| match pinned.get(k) {
+                        Some(p) => p.cost,
+                        None => {
+                            bytes.peek_cost(k.source()).unwrap_or(0)
+                                + decoded.peek_cost(&**k).unwrap_or(0)
+                        }
+                    })
+                    .sum();
+                (tag.to_string(), cost)
+            })
+            .collect::<Vec<_>>();
+        out.sort_unstable();
+        out
+    }
+
+    /// Remove an item from the cache.
+    ///
+    /// This also unpins it and forgets its tags.
+    pub fn remove(&self, key: &K) {
+        let key = &*self.normalize(key);
+        self.tags.write().unwrap().remove_key(key);
+        for keys in self.pinned_tags.lock().unwrap().values_mut() {
+            keys.remove(key);
+        }
+        self.pinned_entries.write().unwrap().remove(key);
+        self.tiers.bytes.lock().unwrap().remove(key.source());
+        self.decoding_guards.lock().unwrap().remove(key);
+        self.tiers.decoded.lock().unwrap().remove(key);
+        self.weak_refs.write().unwrap().remove(key);
+        self.notify_invalidated(key);
+    }
+
+    /// Remove every key for which `keep` returns false from the given parts of the cache.
+    ///
+    /// `keep` sees normalized keys, and may be called more than once for the same key.  Tags are kept.  The bytes tier
+    /// is keyed by source, so for keys such as [ParamKey] whose source is something else, bytes are dropped if `keep`
+    /// rejects any key the cache knows of with that source.
+    pub fn retain(&self, scope: RemovalScope, mut keep: impl FnMut(&K) -> bool) {
+        let rejected_sources = if scope.bytes
+            && self
+                .tiers
+                .bytes
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(s, _)| K::source_as_key(s).is_none())
+        {
+            self.rejected_sources(&mut keep)
+        } else {
+            Default::default()
+        };
+
+        if scope.pinned {
+            // Tag pins go first, matching pin_tag.
+            let mut pinned_tags = self.pinned_tags.lock().unwrap();
+            let mut pinned = self.pinned_entries.write().unwrap();
+            pinned.retain(|k, _| keep(k));
+            for keys in pinned_tags.values_mut() {
+                keys.retain(|k, pin_id| pinned.get(k).is_some_and(|p| p.id == *pin_id));
+            }
+        }
+        if scope.bytes {
+            self.tiers
+                .bytes
+                .lock()
+                .unwrap()
+                .retain(|s, _| match K::source_as_key(s) {
+                    Some(k) => keep(k),
+                    None => !rejected_sources.contains(s),
+                });
+        }
+        if scope.decoded {
+            self.tiers.decoded.lock().unwrap().retain(|k, _| keep(k));
+        }
+        if scope.weak_refs {
+            // Every item the cache knows of has a weak reference, so these are the keys it forgets entirely.
+            let mut forgotten = vec![];
+            self.weak_refs.write().unwrap().retain(|k, _| {
+                let kept = keep(k);
+                if !kept {
+                    forgotten.push(k.clone());
+                }
+                kept
+            });
+            for k in forgotten {
+                self.notify_invalidated(&k);
+            }
+        }
+    }
+
+    /// The sources of every key the cache knows of for which `keep` returns false.
+    fn rejected_sources(
+        &self,
+        keep: &mut impl FnMut(&K) -> bool,
+    ) -> std::collections::HashSet<Arc<K::Source>> {
+        let weak_refs = self.weak_refs.read().unwrap();
+        let pinned = self.pinned_entries.read().unwrap();
+        let decoded = self.tiers.decoded.lock().unwrap();
+        weak_refs
+            .keys()
+            .chain(pinned.keys())
+            .map(|k| &**k)
+            .chain(decoded.iter().map(|(k, _)| k))
+            .filter(|k| !keep(k))
+            .map(|k| k.source().to_shared())
+            .collect()
+    }
+
+    /// Remove everything from the given parts of the cache.  Tags are kept.
+    pub fn clear(&self, scope: RemovalScope) {
+        self.retain(
+            RemovalScope {
+                bytes: false,
+                ..scope
+            },
+            |_| false,
+        );
+        if scope.bytes {
+            self.tiers.bytes.lock().unwrap().clear();
+        }
+    }
+
+    /// The total cost of items which are alive only because something outside the cache holds them.
+    ///
+    /// These are items which were evicted from the decoded tier, or never went into it because they were over
+    /// [AssetCacheConfig::max_single_object_decoded_cost], but which are still in memory.  Also forgets about items
+    /// which have since been dropped.
+    pub fn unmanaged_cost(&self) -> u64 {
+        let mut weak_refs = self.weak_refs.write().unwrap();
+        weak_refs.retain(|_, w| w.item.strong_count() > 0);
+
+        let pinned = self.pinned_entries.read().unwrap();
+        let decoded = self.tiers.decoded.lock().unwrap();
+        weak_refs
+            .iter()
+            .filter(|(k, _)| !pinned.contains_key(*k) && !decoded.contains_key(&**k))
+            .map(|(_, w)| w.cost)
+            .sum()
+    }
+
+    /// Evict least recently used entries from a tier until its cost is at or below `target_cost`.
+    ///
+    /// Pinned entries, and anything still referenced from outside the cache, are unaffected.
+    pub fn trim(&self, tier: Tier, target_cost: u64) {
+        self.tiers.trim(tier, target_cost);
+    }
+
+    /// Shrink both tiers to the given fraction of their current cost, for example in response to memory pressure.
+    ///
+    /// `0.0` empties them.  See also [MemoryMonitor], which can call this automatically.
+    pub fn shrink_to(&self, fraction: f64) {
+        self.tiers.shrink_to(fraction);
+    }
+}
+
+impl<VfsImpl: Vfs, DecoderImpl: Decoder> AssetCache<VfsImpl, DecoderImpl> {
+    /// Get every item under the given prefix, as listed by [Vfs::list].
+    ///
+    /// Fails if the [Vfs] doesn't support listing, or on the first item which fails to load.
+    pub fn get_all(
+        &self,
+        prefix: &str,
+    ) -> Result<KeyedItems<DecoderImpl::Output>, AssetCacheError<DecoderImpl::Error>> {
+        let keys = self.vfs.list(prefix).map_err(AssetCacheError::Vfs)?;
+        keys.into_iter()
+            .map(|k| {
+                let item = self.get(&k)?;
+                Ok((k, item))
+            })
+            .collect()
+    }
+
+    /// Remove every key under a prefix from the given parts of the cache.
+    ///
+    /// The prefix is normalized like a key, keeping a trailing `/`, so `levels/3/` matches `levels/3/map` but not
+    /// `levels/30/map`.  Tags are kept.
+    pub fn remove_prefix(&self, prefix: &str, scope: RemovalScope) {
+        let mut normalized = match &self.key_normalizer {
+            Some(n) => n.normalize(prefix).into_owned(),
+            None => prefix.to_string(),
+        };
+        if prefix.ends_with('/') && !normalized.is_empty() && !normalized.ends_with('/') {
+            normalized.push('/');
+        }
+        let prefix = normalized;
+        self.retain(scope, |k| !k.starts_with(&prefix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// A VFS wrapping a `HashMap` for testing.
+    struct HashMapVfs(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl Vfs for HashMapVfs {
+        type Reader = std::io::Cursor<Vec<u8>>;
+
+        fn open(&self, key: &str) -> Result<Self::Reader, IoError> {
+            let ret = self
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .ok_or_else(|| {
+                    IoError::new(std::io::ErrorKind::NotFound, "Entry not found".to_string())
+                })?
+                .clone();
+            Ok(std::io::Cursor::new(ret))
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>, IoError> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|k| k.starts_with(prefix))
+                .cloned()
+                .collect())
+        }
+    }
+
+    impl VfsReader for std::io::Cursor<Vec<u8>> {
+        fn get_size(&self) -> Result<u64, IoError> {
+            Ok(self.get_ref().len() as u64)
+        }
+    }
+
+    // Add a helper to put things into the vfs.
+    impl HashMapVfs {
+        fn new() -> HashMapVfs {
+            HashMapVfs(Mutex::new(Default::default()))
+        }
+
+        pub fn insert(&self, key: &str, value: Vec<u8>) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().insert(key.to_string(), value)
+        }
+    }
+
+    struct HashMapDecoder;
+
     impl Decoder for HashMapDecoder {
         type Error = IoError;
         type Output = String;
@@ -299,6 +1697,15 @@ mod tests {
         (vfs.clone(), AssetCache::new(vfs, HashMapDecoder, cfg))
     }
 
+    /// [build_cache], normalizing keys with the [DefaultKeyNormalizer].
+    fn build_normalized_cache() -> (Arc<HashMapVfs>, AssetCache<Arc<HashMapVfs>, HashMapDecoder>) {
+        let (vfs, cache) = build_cache();
+        (
+            vfs,
+            cache.with_key_normalizer(DefaultKeyNormalizer::default()),
+        )
+    }
+
     // Test some basic common cache operations.
     #[test]
     fn basic_ops() {
@@ -318,6 +1725,67 @@ mod tests {
         cache.search_for_item("a").expect("Key should be found");
     }
 
+    #[test]
+    fn test_key_normalization() {
+        let (vfs, cache) = build_normalized_cache();
+        vfs.insert("a/b", "abc".into());
+
+        let first = cache.get("a/b").unwrap();
+        for k in &["/a/b", "a//b", "a/./c/../b"] {
+            assert!(Arc::ptr_eq(&first, &cache.get(k).unwrap()), "{}", k);
+        }
+        assert!(cache.get("A/B").is_err());
+
+        cache.remove("/a/./b");
+        assert!(cache.search_for_item("a/b").is_none());
+
+        // Without a normalizer, keys go to the VFS as given.
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(50)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(60)
+            .max_single_object_decoded_cost(12)
+            .build()
+            .unwrap();
+        let plain = AssetCache::new(vfs.clone(), HashMapDecoder, cfg);
+        assert_eq!(&*plain.get("a/b").unwrap(), "abc");
+        assert!(plain.get("/a/b").is_err());
+
+        let (vfs, cache) = build_cache();
+        let cache = cache.with_key_normalizer(DefaultKeyNormalizer { case_fold: true });
+        vfs.insert("a/b", "abc".into());
+        assert!(Arc::ptr_eq(
+            &cache.get("A/B").unwrap(),
+            &cache.get("a/b").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_get_all() {
+        let (vfs, cache) = build_cache();
+        vfs.insert("sfx/a", "a".into());
+        vfs.insert("sfx/b", "b".into());
+        vfs.insert("music/c", "c".into());
+
+        let mut all = cache
+            .get_all("sfx/")
+            .unwrap()
+            .into_iter()
+            .map(|(k, v)| (k, (*v).clone()))
+            .collect::<Vec<_>>();
+        all.sort();
+        assert_eq!(
+            all,
+            vec![
+                ("sfx/a".to_string(), "a".to_string()),
+                ("sfx/b".to_string(), "b".to_string())
+            ]
+        );
+        cache
+            .search_for_item("sfx/a")
+            .expect("Should now be cached");
+    }
+
     #[test]
     fn test_single_object_limits() {
         let (vfs, cache) = build_cache();
@@ -356,6 +1824,324 @@ mod tests {
         assert!(cache.search_for_item(NO_CACHE).is_none());
     }
 
+    fn unified_cache(budget: UnifiedBudget) -> AssetCache<Arc<MemoryVfs>, HashMapDecoder> {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(0)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(10)
+            .unified_budget(budget)
+            .build()
+            .expect("Should build");
+        let vfs = MemoryVfs::new();
+        for k in ["a", "b", "c", "d"] {
+            vfs.insert(k, k.repeat(5).as_bytes());
+        }
+        AssetCache::new(Arc::new(vfs), HashMapDecoder, cfg)
+    }
+
+    fn tier_keys<K: CacheKey + ToString + ?Sized, V>(
+        tier: &Mutex<CostBasedLru<K, V>>,
+    ) -> Vec<String> {
+        let mut keys = tier
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| k.to_string())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_unified_budget() {
+        // Equal priorities: one LRU across both tiers.
+        let cache = unified_cache(
+            UnifiedBudgetBuilder::default()
+                .total_cost(20)
+                .build()
+                .unwrap(),
+        );
+        for k in ["a", "b", "c"] {
+            cache.get(k).unwrap();
+        }
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["b", "c"]);
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["b", "c"]);
+
+        // Strongly prefer keeping decoded outputs.
+        let cache = unified_cache(
+            UnifiedBudgetBuilder::default()
+                .total_cost(20)
+                .decoded_priority(1 << 40)
+                .build()
+                .unwrap(),
+        );
+        for k in ["a", "b", "c", "d"] {
+            cache.get(k).unwrap();
+        }
+        assert!(tier_keys(&cache.tiers.bytes).is_empty());
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["a", "b", "c", "d"]);
+
+        // Prefer evicting bytes, but not below the floor.
+        let cache = unified_cache(
+            UnifiedBudgetBuilder::default()
+                .total_cost(20)
+                .decoded_priority(1 << 40)
+                .bytes_floor(10)
+                .build()
+                .unwrap(),
+        );
+        for k in ["a", "b", "c"] {
+            cache.get(k).unwrap();
+        }
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["b", "c"]);
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_low_watermark_keeps_newest() {
+        let build = |single| {
+            AssetCacheConfigBuilder::default()
+                .max_bytes_cost(100)
+                .max_single_object_bytes_cost(single)
+                .max_decoded_cost(100)
+                .max_single_object_decoded_cost(single)
+                .low_watermark_percent(50)
+                .build()
+        };
+        // An object over the low watermark would evict everything but itself.
+        assert!(build(100).is_err());
+
+        let vfs = Arc::new(MemoryVfs::new());
+        vfs.insert("a", vec![b'a'; 40]);
+        vfs.insert("b", vec![b'b'; 50]);
+        vfs.insert("c", vec![b'c'; 50]);
+        let cache: AssetCache<_, _> = AssetCache::new(vfs, HashMapDecoder, build(50).unwrap());
+        for k in ["a", "b", "c"] {
+            cache.get(k).unwrap();
+        }
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["c"]);
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["c"]);
+    }
+
+    #[test]
+    fn test_shrinking() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(50)
+            .max_decoded_cost(100)
+            .max_single_object_decoded_cost(50)
+            .low_watermark_percent(50)
+            .build()
+            .expect("Should build");
+        let vfs = Arc::new(MemoryVfs::new());
+        for i in 0..10 {
+            vfs.insert(&i.to_string(), "0123456789".as_bytes());
+        }
+        let cache: AssetCache<_, _> = AssetCache::new(vfs.clone(), HashMapDecoder, cfg);
+        for i in 0..10 {
+            cache.get(&i.to_string()).unwrap();
+        }
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 100);
+
+        // Crossing the limit evicts down to the low watermark.
+        vfs.insert("10", "0123456789".as_bytes());
+        cache.get("10").unwrap();
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 50);
+
+        cache.trim(Tier::Bytes, 20);
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["10", "9"]);
+        cache.shrink_to(0.5);
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["10"]);
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 20);
+
+        // Driven by a monitor, with a source we control.
+        let pressure = Arc::new(Mutex::new(0));
+        let source_pressure = pressure.clone();
+        let monitor = MemoryMonitor::start(
+            move || {
+                Ok(MemorySample {
+                    used: *source_pressure.lock().unwrap(),
+                    limit: 100,
+                })
+            },
+            MemoryMonitorConfigBuilder::default()
+                .poll_interval(std::time::Duration::from_secs(3600))
+                .build()
+                .unwrap(),
+        );
+        let cache = cache.with_memory_monitor(&monitor);
+        assert!(!monitor.check().unwrap());
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 20);
+        *pressure.lock().unwrap() = 95;
+        assert!(monitor.check().unwrap());
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 10);
+    }
+
+    /// Decodes to the length, to check that caches with different output types can share a group.
+    struct LenDecoder;
+
+    impl Decoder for LenDecoder {
+        type Error = IoError;
+        type Output = usize;
+
+        fn decode<R: Read>(&self, mut reader: R) -> Result<usize, IoError> {
+            let mut out = vec![];
+            reader.read_to_end(&mut out)?;
+            Ok(out.len())
+        }
+
+        fn estimate_cost(&self, _item: &usize) -> Result<u64, IoError> {
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_cache_group() {
+        let cfg = || {
+            AssetCacheConfigBuilder::default()
+                .max_bytes_cost(1000)
+                .max_single_object_bytes_cost(1000)
+                .max_decoded_cost(1000)
+                .max_single_object_decoded_cost(1000)
+                .build()
+                .expect("Should build")
+        };
+        let vfs = Arc::new(MemoryVfs::new());
+        for k in ["a", "b", "c"] {
+            vfs.insert(k, k.repeat(10).as_bytes());
+        }
+
+        let group = CacheGroup::new(40);
+        let strings = AssetCache::new(vfs.clone(), HashMapDecoder, cfg()).with_group(&group);
+        let lens = AssetCache::new(vfs.clone(), LenDecoder, cfg()).with_group(&group);
+
+        // 20 for the string, 11 for the length.
+        strings.get("a").unwrap();
+        lens.get("b").unwrap();
+        assert_eq!(group.current_cost(), 31);
+
+        // Going over evicts the oldest entries across both caches, which here are both tiers of "a".
+        strings.get("c").unwrap();
+        assert_eq!(group.current_cost(), 31);
+        assert!(strings.search_for_item("a").is_none());
+        assert!(strings.search_for_item("c").is_some());
+        assert!(lens.search_for_item("b").is_some());
+
+        // Then the oldest entry is the bytes of "b", in the other cache.
+        lens.get("a").unwrap();
+        assert_eq!(group.current_cost(), 32);
+        assert_eq!(tier_keys(&lens.tiers.bytes), vec!["a"]);
+        assert_eq!(tier_keys(&lens.tiers.decoded), vec!["a", "b"]);
+
+        // Dropping a cache removes it from the group.
+        std::mem::drop(strings);
+        assert_eq!(group.current_cost(), 12);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compressed_bytes() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(1000)
+            .max_single_object_bytes_cost(1000)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .bytes_compression(BytesCompression::Lz4)
+            .build()
+            .expect("Should build");
+        let vfs = Arc::new(HashMapVfs::new());
+        let cache = AssetCache::new(vfs.clone(), HashMapDecoder, cfg);
+
+        let text = "abcdefgh".repeat(100);
+        vfs.insert("text", text.clone().into());
+        assert_eq!(*cache.get("text").unwrap(), text);
+        assert!(cache.tiers.bytes.lock().unwrap().current_cost() < 100);
+
+        // With nothing in the decoded tier, this has to go through the compressed bytes again.
+        vfs.insert("text", "changed".into());
+        assert_eq!(*cache.get("text").unwrap(), text);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mapped_bytes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let big = "a".repeat(1000);
+        let huge = "b".repeat(3000);
+        std::fs::write(tmp_dir.path().join("small"), "small").unwrap();
+        std::fs::write(tmp_dir.path().join("big"), &big).unwrap();
+        std::fs::write(tmp_dir.path().join("huge"), &huge).unwrap();
+
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(1000)
+            .max_single_object_bytes_cost(200)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .mapped_bytes_cost_percent(10)
+            .build()
+            .expect("Should build");
+        // Safety: nothing modifies the temporary directory while the test runs.
+        let vfs = unsafe {
+            FilesystemVfs::new(tmp_dir.path())
+                .unwrap()
+                .with_mmap_threshold(500)
+        };
+        let cache = AssetCache::new(vfs, HashMapDecoder, cfg);
+
+        assert_eq!(*cache.get("small").unwrap(), "small");
+        assert_eq!(cache.tiers.bytes.lock().unwrap().current_cost(), 5);
+
+        // Big files are mapped and charged at 10%.
+        assert_eq!(*cache.get("big").unwrap(), big);
+        assert_eq!(cache.tiers.bytes.lock().unwrap().current_cost(), 105);
+
+        // Even at 10%, this one is over the single object limit, but it still decodes from the mapping.
+        assert_eq!(*cache.get("huge").unwrap(), huge);
+        assert_eq!(cache.tiers.bytes.lock().unwrap().current_cost(), 105);
+    }
+
+    struct SliceDecoder;
+
+    impl Decoder for SliceDecoder {
+        type Error = IoError;
+        type Output = SharedBytes;
+
+        fn decode<R: Read>(&self, mut reader: R) -> Result<SharedBytes, IoError> {
+            let mut out = vec![];
+            reader.read_to_end(&mut out)?;
+            Ok(SharedBytes::from(out).slice(1..))
+        }
+
+        fn estimate_cost(&self, item: &SharedBytes) -> Result<u64, IoError> {
+            Ok(item.len() as u64)
+        }
+
+        fn decode_shared(&self, bytes: SharedBytes) -> Result<SharedBytes, IoError> {
+            Ok(bytes.slice(1..))
+        }
+    }
+
+    #[test]
+    fn test_decode_shared() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(100)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .build()
+            .expect("Should build");
+        let vfs = Arc::new(MemoryVfs::new());
+        let cache = AssetCache::new(vfs.clone(), SliceDecoder, cfg);
+        vfs.insert("a", &b"abc"[..]);
+
+        // With nothing in the decoded tier, both of these come from the same cached bytes without copying.
+        let first = (*cache.get("a").unwrap()).clone();
+        assert_eq!(&*first, b"bc");
+        let second = cache.get("a").unwrap();
+        assert!(second.shares_buffer_with(&first));
+    }
+
     /// If we cache objects which are otherwise too large for the cache, or if an object is purged, we can still get the
     /// objects via our internal cache of weak references.
     #[test]
@@ -372,8 +2158,8 @@ mod tests {
         }
 
         // Let's verify that key "1" isn't in any of the places we expect it to be.
-        assert!(cache.bytes_cache.lock().unwrap().get("1").is_none());
-        assert!(cache.decoded_cache.lock().unwrap().get("1").is_none());
+        assert!(cache.tiers.bytes.lock().unwrap().get("1").is_none());
+        assert!(cache.tiers.decoded.lock().unwrap().get("1").is_none());
         // But it should be in the weak map.
         assert!(cache.weak_refs.read().unwrap().get("1").is_some());
 
@@ -388,11 +2174,589 @@ mod tests {
         // anyway.
         vfs.insert("big", "abcdefghijklmnopqrstuvwxyz".into());
         let sref = cache.get("big");
-        assert!(cache.bytes_cache.lock().unwrap().get("big").is_none());
-        assert!(cache.decoded_cache.lock().unwrap().get("big").is_none());
+        assert!(cache.tiers.bytes.lock().unwrap().get("big").is_none());
+        assert!(cache.tiers.decoded.lock().unwrap().get("big").is_none());
         assert_eq!(&*cache.get("big").unwrap(), "abcdefghijklmnopqrstuvwxyz");
         // But droping sref makes it go away.
         std::mem::drop(sref);
         assert!(cache.search_for_item("big").is_none());
     }
+
+    #[test]
+    fn test_unmanaged_cost() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(100)
+            .max_decoded_cost(20)
+            .max_single_object_decoded_cost(20)
+            .count_unmanaged_cost(true)
+            .build()
+            .expect("Should build");
+        let vfs = Arc::new(MemoryVfs::new());
+        let cache = AssetCache::new(vfs.clone(), HashMapDecoder, cfg);
+        vfs.insert("a", "aaaaa".as_bytes());
+        vfs.insert("b", "bbbbb".as_bytes());
+        vfs.insert("c", "cccccccccc".as_bytes());
+        vfs.insert("d", "ddddd".as_bytes());
+
+        // Things in the decoded tier aren't unmanaged, even while held.
+        let a = cache.get("a").unwrap();
+        cache.get("b").unwrap();
+        cache.get("c").unwrap();
+        assert_eq!(cache.unmanaged_cost(), 0);
+
+        // Inserting d evicts a, which we still hold.  Counting it means there's no room for b either.
+        cache.get("d").unwrap();
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["c", "d"]);
+        assert_eq!(cache.unmanaged_cost(), 5);
+
+        drop(a);
+        assert_eq!(cache.unmanaged_cost(), 0);
+        assert!(cache.weak_refs.read().unwrap().get("a").is_none());
+    }
+
+    #[test]
+    fn test_pin_guards() {
+        let (vfs, cache) = build_normalized_cache();
+        for k in ["a", "b", "c"] {
+            vfs.insert(k, "0123456789".into());
+        }
+
+        let first = cache.pin("/a").unwrap();
+        let second = cache.pin("a").unwrap();
+        assert_eq!(first.key(), "a");
+        assert_eq!(&*second, "0123456789");
+        assert_eq!(cache.pinned_entries(), vec![("a".to_string(), 10)]);
+        assert!(!cache.tiers.decoded.lock().unwrap().contains_key("a"));
+
+        drop(first);
+        assert_eq!(cache.pinned_cost(), 10);
+        drop(second);
+        assert!(cache.pinned_entries().is_empty());
+        // It's back under LRU management rather than gone.
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["a"]);
+
+        // Unpinning something pinned with cache_always leaves it cached, but guards still hold it.
+        cache.cache_always("b", Arc::new("bb".into())).unwrap();
+        let guard = cache.pin("b").unwrap();
+        cache.unpin("b");
+        assert_eq!(cache.pinned_entries(), vec![("b".to_string(), 2)]);
+        drop(guard);
+        assert!(cache.pinned_entries().is_empty());
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["a", "b"]);
+        assert_eq!(&*cache.get("b").unwrap(), "bb");
+
+        // Removing an item while it is pinned doesn't bring it back when the guard drops.
+        let guard = cache.pin("c").unwrap();
+        cache.remove("c");
+        drop(guard);
+        assert!(!cache.tiers.decoded.lock().unwrap().contains_key("c"));
+
+        // Nor does a stale guard release a pin taken after the removal.
+        let stale = cache.pin("c").unwrap();
+        cache.clear(RemovalScope::ALL);
+        let fresh = cache.pin("c").unwrap();
+        drop(stale);
+        assert_eq!(cache.pinned_entries(), vec![("c".to_string(), 10)]);
+        drop(fresh);
+        assert!(cache.pinned_entries().is_empty());
+    }
+
+    #[test]
+    fn test_tags() {
+        let (vfs, cache) = build_normalized_cache();
+        for (k, v) in [("a", "aaaa"), ("b", "bbbbb"), ("c", "cccccc")] {
+            vfs.insert(k, v.into());
+        }
+
+        cache.get_tagged("/a", &["level", "common"]).unwrap();
+        cache.get_tagged("b", &["level"]).unwrap();
+        cache.get_tagged("c", &["menu"]).unwrap();
+        assert_eq!(
+            cache.tag_costs(),
+            vec![
+                ("common".to_string(), 8),
+                ("level".to_string(), 18),
+                ("menu".to_string(), 12)
+            ]
+        );
+
+        cache.evict_tag("level");
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["c"]);
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["c"]);
+
+        // Tags survive eviction, so pinning the tag brings its items back.
+        cache.pin_tag("level").unwrap();
+        assert_eq!(
+            cache.pinned_entries(),
+            vec![("a".to_string(), 4), ("b".to_string(), 5)]
+        );
+        // Pins stack with other pins, and items tagged while the tag is pinned are pinned too.
+        let guard = cache.pin("a").unwrap();
+        cache.get_tagged("c", &["level"]).unwrap();
+        assert_eq!(cache.pinned_entries().len(), 3);
+        cache.evict_tag("level");
+        assert_eq!(&*cache.search_for_item("b").unwrap(), "bbbbb");
+
+        cache.unpin_tag("level");
+        assert_eq!(cache.pinned_entries(), vec![("a".to_string(), 4)]);
+        drop(guard);
+        assert!(cache.pinned_entries().is_empty());
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["a", "b", "c"]);
+
+        // Only the decoded outputs of b and c are left, since evicting the tag dropped their bytes.
+        cache.remove("a");
+        assert_eq!(
+            cache.tag_costs(),
+            vec![("level".to_string(), 11), ("menu".to_string(), 6)]
+        );
+
+        // Unpinning a tag whose pins were cleared leaves later pins alone.
+        cache.pin_tag("menu").unwrap();
+        cache.clear(RemovalScope::ALL);
+        let guard = cache.pin("c").unwrap();
+        cache.unpin_tag("menu");
+        assert_eq!(cache.pinned_entries(), vec![("c".to_string(), 6)]);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_bulk_removal() {
+        let (vfs, cache) = build_normalized_cache();
+        for k in ["levels/3/map", "levels/3/music", "levels/30/map", "ui/font"] {
+            vfs.insert(k, "x".into());
+            cache.get(k).unwrap();
+        }
+        cache
+            .cache_always("levels/3/boss", Arc::new("boss".into()))
+            .unwrap();
+
+        cache.remove_prefix("/levels/3/", RemovalScope::CACHED);
+        assert_eq!(
+            tier_keys(&cache.tiers.decoded),
+            vec!["levels/30/map", "ui/font"]
+        );
+        assert_eq!(
+            tier_keys(&cache.tiers.bytes),
+            vec!["levels/30/map", "ui/font"]
+        );
+        assert!(cache.search_for_item("levels/3/boss").is_some());
+
+        cache.remove_prefix(
+            "levels/3/",
+            RemovalScope {
+                pinned: true,
+                ..RemovalScope::CACHED
+            },
+        );
+        assert!(cache.pinned_entries().is_empty());
+
+        // Only the decoded tier, keeping one key.
+        let held = cache.get("ui/font").unwrap();
+        cache.retain(
+            RemovalScope {
+                bytes: false,
+                ..RemovalScope::CACHED
+            },
+            |k| k == "levels/30/map",
+        );
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["levels/30/map"]);
+        assert_eq!(tier_keys(&cache.tiers.bytes).len(), 2);
+        // Weak references weren't touched, so the held item comes back without decoding.
+        assert!(Arc::ptr_eq(&cache.get("ui/font").unwrap(), &held));
+
+        cache.clear(RemovalScope::ALL);
+        assert!(tier_keys(&cache.tiers.decoded).is_empty());
+        assert!(tier_keys(&cache.tiers.bytes).is_empty());
+        assert!(cache.search_for_item("ui/font").is_none());
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let (vfs, cache) = build_normalized_cache();
+        let cache = cache
+            .with_fallback_resolver(|key, err| {
+                assert!(matches!(err, AssetCacheError::Vfs(_)));
+                key.starts_with("textures/")
+                    .then(|| "/textures/missing".to_string())
+            })
+            .with_fallback_value(Arc::new("default".into()));
+        vfs.insert("textures/missing", "checker".into());
+
+        let res = cache.get_or_fallback("textures/wall").unwrap();
+        assert!(res.is_fallback());
+        assert_eq!(&*res.item, "checker");
+        assert!(cache.get("textures/wall").is_err());
+
+        // No fallback key, so the value.
+        let res = cache.get_or_fallback("sounds/boom").unwrap();
+        assert_eq!(&*res.item, "default");
+
+        // Fixing the asset is picked up, since the fallback wasn't cached under its key.
+        vfs.insert("textures/wall", "bricks".into());
+        let res = cache.get_or_fallback("textures/wall").unwrap();
+        assert!(!res.is_fallback());
+        assert_eq!(&*res.item, "bricks");
+
+        // Without a value, failures whose fallback key also fails are errors.
+        let (_, cache) = build_cache();
+        let cache = cache.with_fallback_resolver(|_, _| Some("also_missing".into()));
+        assert!(matches!(
+            cache.get_or_fallback("a"),
+            Err(AssetCacheError::Vfs(_))
+        ));
+    }
+
+    #[test]
+    fn test_handles() {
+        let (vfs, cache) = build_normalized_cache();
+        vfs.insert("a", "v1".into());
+        vfs.insert("b", "b1".into());
+
+        let handle = cache.get_handle("a").unwrap();
+        let other = cache.get_handle("/a").unwrap();
+        let old = cache.get("a").unwrap();
+        assert_eq!(&*handle.load(), "v1");
+        assert_eq!(handle.generation(), 0);
+
+        cache.replace("a", "v2".into()).unwrap();
+        assert_eq!(&*handle.load(), "v2");
+        assert_eq!(&*other.load(), "v2");
+        assert_eq!(other.generation(), 1);
+        assert_eq!(&*old, "v1");
+        assert_eq!(&*cache.get("a").unwrap(), "v2");
+        // The bytes for v1 are gone, so nothing can decode them back.
+        assert!(!cache.tiers.bytes.lock().unwrap().contains_key("a"));
+
+        vfs.insert("a", "v3".into());
+        cache.reload("a").unwrap();
+        assert_eq!(&*handle.load(), "v3");
+        assert_eq!(handle.generation(), 2);
+        assert!(cache.reload("missing").is_err());
+        assert_eq!(handle.generation(), 2);
+
+        // Pinned items stay pinned across replacement.
+        let b = cache.get_handle("b").unwrap();
+        cache.cache_always("b", Arc::new("pinned".into())).unwrap();
+        cache.replace("b", "b2".into()).unwrap();
+        assert_eq!(cache.pinned_entries(), vec![("b".to_string(), 2)]);
+        assert_eq!(&*b.load(), "b2");
+        assert_eq!(&*cache.get("b").unwrap(), "b2");
+
+        // Once every handle is gone, a new one starts afresh.
+        drop((handle, other));
+        assert_eq!(cache.get_handle("a").unwrap().generation(), 0);
+    }
+
+    #[test]
+    fn test_pinned_budget() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(50)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(60)
+            .max_single_object_decoded_cost(12)
+            .max_pinned_cost(10)
+            .build()
+            .expect("Should build");
+        let cache = AssetCache::new(Arc::new(MemoryVfs::new()), HashMapDecoder, cfg)
+            .with_key_normalizer(DefaultKeyNormalizer::default());
+
+        cache.cache_always("/b", Arc::new("bbbb".into())).unwrap();
+        cache
+            .cache_always_with_cost("a", Arc::new("a".into()), 5)
+            .unwrap();
+        assert_eq!(
+            cache.pinned_entries(),
+            vec![("a".to_string(), 5), ("b".to_string(), 4)]
+        );
+        assert_eq!(cache.pinned_cost(), 9);
+
+        assert!(matches!(
+            cache.cache_always("c", Arc::new("cc".into())),
+            Err(AssetCacheError::PinnedBudgetExceeded {
+                cost: 2,
+                max_pinned_cost: 10
+            })
+        ));
+        assert!(cache.get("c").is_err());
+
+        // Replacing a pin only counts the new cost.
+        cache.cache_always("b", Arc::new("bbbbb".into())).unwrap();
+        assert_eq!(cache.pinned_cost(), 10);
+        assert_eq!(&*cache.get("b").unwrap(), "bbbbb");
+
+        cache.remove("a");
+        assert_eq!(cache.pinned_entries(), vec![("b".to_string(), 5)]);
+    }
+
+    /// Serves assets by numeric id, as an engine with interned asset ids might.
+    struct IdVfs(Vec<&'static [u8]>);
+
+    impl Vfs<u64> for IdVfs {
+        type Reader = std::io::Cursor<&'static [u8]>;
+
+        fn open(&self, key: &u64) -> Result<Self::Reader, IoError> {
+            self.0
+                .get(*key as usize)
+                .map(|b| std::io::Cursor::new(*b))
+                .ok_or_else(|| IoError::new(std::io::ErrorKind::NotFound, "No such id"))
+        }
+    }
+
+    #[test]
+    fn test_non_string_keys() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(50)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(60)
+            .max_single_object_decoded_cost(12)
+            .build()
+            .expect("Should build");
+        let cache: AssetCache<_, _, u64> =
+            AssetCache::new(IdVfs(vec![b"zero", b"one", b"two"]), HashMapDecoder, cfg)
+                .with_fallback_resolver(|_, _| Some(0));
+
+        let one = cache.get(&1).unwrap();
+        assert_eq!(&*one, "one");
+        assert!(Arc::ptr_eq(&one, &cache.get(&1).unwrap()));
+        assert_eq!(tier_keys(&cache.tiers.decoded), vec!["1"]);
+
+        let guard = cache.pin(&2).unwrap();
+        assert_eq!(*guard.key(), 2);
+        assert_eq!(cache.pinned_entries(), vec![(2, 3)]);
+        drop(guard);
+
+        assert_eq!(&*cache.get_or_fallback(&7).unwrap().item, "zero");
+        cache.remove(&1);
+        assert!(cache.search_for_item(&1).is_none());
+    }
+
+    /// Decodes to a prefix of the content, with the length as a parameter.
+    struct PrefixDecoder;
+
+    impl Decoder for PrefixDecoder {
+        type Error = IoError;
+        type Output = String;
+
+        fn decode<R: Read + std::io::Seek>(&self, reader: R) -> Result<String, IoError> {
+            HashMapDecoder.decode(reader)
+        }
+
+        fn estimate_cost(&self, item: &String) -> Result<u64, IoError> {
+            Ok(item.len() as u64)
+        }
+    }
+
+    impl DecodeWith<usize> for PrefixDecoder {
+        fn decode_with<R: Read + std::io::Seek>(
+            &self,
+            reader: R,
+            len: &usize,
+        ) -> Result<String, IoError> {
+            Ok(self.decode(reader)?.chars().take(*len).collect())
+        }
+    }
+
+    #[test]
+    fn test_param_keys() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(50)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(60)
+            .max_single_object_decoded_cost(12)
+            .build()
+            .expect("Should build");
+        let vfs = Arc::new(MemoryVfs::new());
+        vfs.insert("tex", "abcdef".as_bytes());
+        let cache: AssetCache<_, _, ParamKey<usize>> = AssetCache::new(vfs, PrefixDecoder, cfg)
+            .with_key_normalizer(DefaultKeyNormalizer::default());
+
+        // The first decode reads the file, and the second shares its bytes.
+        let mut bytes_read = 0;
+        let two = cache
+            .find_or_decode(&ParamKey::new("tex", 2), &mut bytes_read)
+            .unwrap();
+        let four = cache
+            .find_or_decode(&cache.normalize(&ParamKey::new("/tex", 4)), &mut bytes_read)
+            .unwrap();
+        assert_eq!((two.as_str(), four.as_str()), ("ab", "abcd"));
+        assert_eq!(bytes_read, 6);
+        assert_eq!(tier_keys(&cache.tiers.bytes), vec!["tex"]);
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 6);
+        assert!(Arc::ptr_eq(
+            &four,
+            &cache.get(&ParamKey::new("tex", 4)).unwrap()
+        ));
+
+        // Rejecting one set of parameters drops the shared bytes, but not the others' outputs.
+        cache.retain(RemovalScope::CACHED, |k| k.params != 2);
+        assert!(tier_keys(&cache.tiers.bytes).is_empty());
+        assert_eq!(cache.tiers.decoded.lock().unwrap().current_cost(), 4);
+    }
+
+    /// Serves content with a hash, as if from a build manifest.  The hash isn't checked against the content, so tests
+    /// can tell which was used.
+    struct HashedVfs(Mutex<(&'static [u8], Option<u64>)>);
+
+    struct HashedReader {
+        inner: std::io::Cursor<&'static [u8]>,
+        hash: Option<u64>,
+    }
+
+    impl Read for HashedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl std::io::Seek for HashedReader {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl VfsReader for HashedReader {
+        fn get_size(&self) -> Result<u64, IoError> {
+            Ok(self.inner.get_ref().len() as u64)
+        }
+
+        fn content_hash(&self) -> Option<u64> {
+            self.hash
+        }
+    }
+
+    impl HashedVfs {
+        fn set(&self, content: &'static [u8], hash: Option<u64>) {
+            *self.0.lock().unwrap() = (content, hash);
+        }
+    }
+
+    impl Vfs for HashedVfs {
+        type Reader = HashedReader;
+
+        fn open(&self, _key: &str) -> Result<HashedReader, IoError> {
+            let (content, hash) = *self.0.lock().unwrap();
+            Ok(HashedReader {
+                inner: std::io::Cursor::new(content),
+                hash,
+            })
+        }
+    }
+
+    /// Claims every entry is one byte long.
+    struct UnderReportingVfs(&'static [u8]);
+
+    struct UnderReportingReader(std::io::Cursor<&'static [u8]>);
+
+    impl Read for UnderReportingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl std::io::Seek for UnderReportingReader {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl VfsReader for UnderReportingReader {
+        fn get_size(&self) -> Result<u64, IoError> {
+            Ok(1)
+        }
+    }
+
+    impl Vfs for UnderReportingVfs {
+        type Reader = UnderReportingReader;
+
+        fn open(&self, _key: &str) -> Result<UnderReportingReader, IoError> {
+            Ok(UnderReportingReader(std::io::Cursor::new(self.0)))
+        }
+    }
+
+    #[test]
+    fn test_size_hints() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(20)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(100)
+            .max_single_object_decoded_cost(100)
+            .build()
+            .unwrap();
+        let content = &b"more than the bytes tier can hold"[..];
+        let cache = AssetCache::new(UnderReportingVfs(content), HashMapDecoder, cfg);
+        assert_eq!(cache.get("a").unwrap().as_bytes(), content);
+        assert_eq!(cache.tiers.bytes.lock().unwrap().current_cost(), 0);
+    }
+
+    #[test]
+    fn test_content_hashes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let vfs = Arc::new(HashedVfs(Mutex::new((b"one", Some(1)))));
+        let build = |validate: bool| {
+            let cfg = AssetCacheConfigBuilder::default()
+                .max_bytes_cost(100)
+                .max_single_object_bytes_cost(100)
+                .max_decoded_cost(100)
+                .max_single_object_decoded_cost(100)
+                .validate_content_hash(validate)
+                .build()
+                .unwrap();
+            AssetCache::new(vfs.clone(), HashMapDecoder, cfg)
+                .with_disk_tier(
+                    DiskTierConfigBuilder::default()
+                        .directory(tmp_dir.path())
+                        .max_cost(1000)
+                        .build()
+                        .unwrap(),
+                )
+                .unwrap()
+        };
+
+        // Without validation, decoded items are served until they go, but the bytes and disk tiers notice the change.
+        let cache = build(false);
+        assert_eq!(&*cache.get("a").unwrap(), "one");
+        vfs.set(b"two", Some(2));
+        assert_eq!(&*cache.get("a").unwrap(), "one");
+        cache.trim(Tier::Decoded, 0);
+        assert_eq!(&*cache.get("a").unwrap(), "two");
+
+        // The disk tier matches on the hash alone.
+        vfs.set(b"xxx", Some(2));
+        assert_eq!(&*build(false).get("a").unwrap(), "two");
+
+        // With validation, a change is picked up even while the item is alive elsewhere.
+        let cache = build(true);
+        let two = cache.get("a").unwrap();
+        assert_eq!(&*two, "two");
+        vfs.set(b"three", Some(3));
+        assert_eq!(&*cache.get("a").unwrap(), "three");
+        assert_eq!(&*two, "two");
+
+        // Readers without a hash are assumed current.
+        vfs.set(b"four", None);
+        assert_eq!(&*cache.get("a").unwrap(), "three");
+
+        // Stale items are replaced like a reload, including pinned ones.
+        let handle = cache.get_handle("a").unwrap();
+        let guard = cache.pin("a").unwrap();
+        let invalidations = Arc::new(Invalidations::default());
+        let listener: Arc<dyn InvalidationListener<str>> = invalidations.clone();
+        cache.add_invalidation_listener(Arc::downgrade(&listener));
+        vfs.set(b"five", Some(5));
+        assert_eq!(&*cache.get("a").unwrap(), "five");
+        assert_eq!(&*handle.load(), "five");
+        assert_eq!(cache.pinned_entries(), vec![("a".to_string(), 4)]);
+        assert_eq!(*invalidations.0.lock().unwrap(), vec!["a"]);
+        drop(guard);
+    }
+
+    #[derive(Default)]
+    struct Invalidations(Mutex<Vec<String>>);
+
+    impl InvalidationListener<str> for Invalidations {
+        fn invalidate(&self, key: &str) {
+            self.0.lock().unwrap().push(key.to_string());
+        }
+    }
 }
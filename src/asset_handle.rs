@@ -0,0 +1,71 @@
+//! [AssetHandle]s, which always point at the latest version of an asset.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+/// The state shared by every handle to one key.
+pub(crate) struct HandleSlot<T> {
+    current: ArcSwap<T>,
+    generation: AtomicU64,
+}
+
+impl<T> HandleSlot<T> {
+    pub(crate) fn new(value: Arc<T>) -> HandleSlot<T> {
+        HandleSlot {
+            current: ArcSwap::new(value),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Make `value` the current version for every handle.
+    pub(crate) fn publish(&self, value: Arc<T>) {
+        self.current.store(value);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// A handle to an asset which follows it across reloads, from [AssetCache::get_handle](crate::AssetCache::get_handle).
+///
+/// Where an `Arc` from [AssetCache::get](crate::AssetCache::get) is stuck with the version it was given, a handle
+/// always loads the current one: [AssetCache::replace](crate::AssetCache::replace) and
+/// [AssetCache::reload](crate::AssetCache::reload) publish new versions to every outstanding handle for the key.
+/// Loading is lock-free, so it's fine to do every frame.
+///
+/// Handles also count each version, so consumers which derive something from the asset can check
+/// [AssetHandle::generation] to see whether they need to redo it.  Clones share the same asset.
+pub struct AssetHandle<T> {
+    slot: Arc<HandleSlot<T>>,
+}
+
+impl<T> AssetHandle<T> {
+    pub(crate) fn new(slot: Arc<HandleSlot<T>>) -> AssetHandle<T> {
+        AssetHandle { slot }
+    }
+
+    /// Get the current version of the asset.
+    pub fn load(&self) -> Arc<T> {
+        self.slot.current.load_full()
+    }
+
+    /// How many times a new version has been published to this handle.  Starts at 0.
+    pub fn generation(&self) -> u64 {
+        self.slot.generation.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for AssetHandle<T> {
+    fn clone(&self) -> AssetHandle<T> {
+        AssetHandle {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for AssetHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AssetHandle")
+            .field("generation", &self.generation())
+            .finish()
+    }
+}
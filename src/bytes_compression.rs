@@ -0,0 +1,203 @@
+//! Optional compression of the bytes tier of the [AssetCache](crate::AssetCache).
+//!
+//! For text-like assets, keeping the raw bytes wastes most of the bytes budget.  When compression is enabled, entries
+//! are compressed on insert and charged at their compressed size, then decompressed into a per-thread scratch buffer
+//! whenever they are handed to [Decoder::decode_bytes](crate::Decoder::decode_bytes).  The scratch buffer is only kept
+//! between decompressions while it is small, since it isn't charged to any budget.  Entries which don't compress
+//! well are stored as [SharedBytes], like they would be without compression.
+//!
+//! Entries whose [VfsReader](crate::VfsReader) provides [mapped bytes](crate::VfsReader::mapped_bytes) are held as-is,
+//! without copying or compressing.
+#[cfg(feature = "lz4")]
+use std::cell::RefCell;
+
+use crate::{DecodeWith, SharedBytes};
+
+/// How the bytes tier stores its entries.
+///
+/// More schemes may be added, so matches on this need a wildcard arm.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum BytesCompression {
+    /// Store the bytes as they came from the [Vfs](crate::Vfs).
+    #[default]
+    None,
+    /// Compress with LZ4, which is fast enough to decompress on every decode.  Requires the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Entries smaller than this aren't worth compressing.
+#[cfg(feature = "lz4")]
+const MIN_COMPRESSIBLE_LEN: usize = 64;
+
+/// Compression has to save at least 1/this of the size to be worth it.
+#[cfg(feature = "lz4")]
+const MIN_SAVINGS_FRACTION: usize = 8;
+
+/// Scratch buffers bigger than this aren't kept between decompressions, since no budget accounts for them.
+#[cfg(feature = "lz4")]
+const MAX_RETAINED_SCRATCH: usize = 64 * 1024;
+
+#[cfg(feature = "lz4")]
+thread_local! {
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+}
+
+/// An entry in the bytes tier.
+pub(crate) struct BytesEntry {
+    stored: StoredBytes,
+    /// The [content hash](crate::VfsReader::content_hash) of what was read, if the reader had one.
+    content_hash: Option<u64>,
+}
+
+enum StoredBytes {
+    /// Uncompressed, either read from the reader or shared with it.
+    Shared { bytes: SharedBytes, cost: u64 },
+    #[cfg(feature = "lz4")]
+    Lz4 { data: Vec<u8>, len: usize },
+}
+
+impl BytesEntry {
+    pub(crate) fn new(raw: Vec<u8>, compression: BytesCompression) -> BytesEntry {
+        let stored = match compression {
+            BytesCompression::None => StoredBytes::raw(raw),
+            #[cfg(feature = "lz4")]
+            BytesCompression::Lz4 => StoredBytes::compressed(raw),
+        };
+        BytesEntry {
+            stored,
+            content_hash: None,
+        }
+    }
+
+    /// Hold a mapped buffer, charging `cost_percent` percent of its length.
+    pub(crate) fn mapped(bytes: SharedBytes, cost_percent: u64) -> BytesEntry {
+        let cost = mapped_cost(bytes.len() as u64, cost_percent);
+        BytesEntry {
+            stored: StoredBytes::Shared { bytes, cost },
+            content_hash: None,
+        }
+    }
+
+    pub(crate) fn with_content_hash(mut self, content_hash: Option<u64>) -> BytesEntry {
+        self.content_hash = content_hash;
+        self
+    }
+
+    pub(crate) fn content_hash(&self) -> Option<u64> {
+        self.content_hash
+    }
+
+    /// What this entry costs in the bytes tier: its size as stored.
+    pub(crate) fn cost(&self) -> u64 {
+        match &self.stored {
+            StoredBytes::Shared { cost, .. } => *cost,
+            #[cfg(feature = "lz4")]
+            StoredBytes::Lz4 { data, .. } => data.len() as u64,
+        }
+    }
+
+    /// Run a closure over the uncompressed bytes of this entry.
+    #[cfg(feature = "lz4")]
+    pub(crate) fn with_bytes<T>(&self, closure: impl FnOnce(&[u8]) -> T) -> T {
+        match &self.stored {
+            StoredBytes::Shared { bytes, .. } => closure(bytes),
+            StoredBytes::Lz4 { data, len } => {
+                // Take the buffer rather than borrowing it, in case the closure ends up back here (for example a
+                // decoder which loads other assets from the same cache).
+                let mut scratch = SCRATCH.with(|s| std::mem::take(&mut *s.borrow_mut()));
+                scratch.resize(*len, 0);
+                let got = lz4_flex::block::decompress_into(data, &mut scratch[..])
+                    .expect("We compressed this ourselves, so it should decompress");
+                debug_assert_eq!(got, *len);
+                let res = closure(&scratch[..]);
+                if scratch.capacity() <= MAX_RETAINED_SCRATCH {
+                    SCRATCH.with(|s| *s.borrow_mut() = scratch);
+                }
+                res
+            }
+        }
+    }
+
+    /// Decode this entry, sharing the bytes with the decoder if they aren't compressed.
+    pub(crate) fn decode<P, D: DecodeWith<P>>(
+        &self,
+        decoder: &D,
+        params: &P,
+    ) -> Result<D::Output, D::Error> {
+        match &self.stored {
+            StoredBytes::Shared { bytes, .. } => decoder.decode_shared_with(bytes.clone(), params),
+            #[cfg(feature = "lz4")]
+            StoredBytes::Lz4 { .. } => self.with_bytes(|b| decoder.decode_bytes_with(b, params)),
+        }
+    }
+}
+
+impl StoredBytes {
+    fn raw(raw: Vec<u8>) -> StoredBytes {
+        StoredBytes::Shared {
+            cost: raw.len() as u64,
+            bytes: raw.into(),
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    fn compressed(raw: Vec<u8>) -> StoredBytes {
+        if raw.len() < MIN_COMPRESSIBLE_LEN {
+            return StoredBytes::raw(raw);
+        }
+
+        let data = lz4_flex::block::compress(&raw);
+        if data.len() > raw.len() - raw.len() / MIN_SAVINGS_FRACTION {
+            return StoredBytes::raw(raw);
+        }
+
+        StoredBytes::Lz4 {
+            data,
+            len: raw.len(),
+        }
+    }
+}
+
+/// The cost charged for a mapped buffer of `len` bytes.
+pub(crate) fn mapped_cost(len: u64, cost_percent: u64) -> u64 {
+    (len as u128 * cost_percent as u128 / 100).min(u64::MAX as u128) as u64
+}
+
+#[cfg(all(test, feature = "lz4"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lz4_entries() {
+        let text = "abcdefgh".repeat(100).into_bytes();
+        let entry = BytesEntry::new(text.clone(), BytesCompression::Lz4);
+        assert!(matches!(entry.stored, StoredBytes::Lz4 { .. }));
+        assert!(entry.cost() < text.len() as u64 / 2);
+        entry.with_bytes(|b| assert_eq!(b, &text[..]));
+
+        // Big buffers aren't kept around afterwards.
+        let big = "abcdefgh".repeat(MAX_RETAINED_SCRATCH).into_bytes();
+        BytesEntry::new(big.clone(), BytesCompression::Lz4).with_bytes(|b| assert_eq!(b, &big[..]));
+        assert!(SCRATCH.with(|s| s.borrow().capacity()) <= MAX_RETAINED_SCRATCH);
+
+        // Too small to bother.
+        let small = BytesEntry::new(b"abc".to_vec(), BytesCompression::Lz4);
+        assert!(matches!(small.stored, StoredBytes::Shared { .. }));
+
+        // Incompressible.
+        let mut state = 12345u64;
+        let noise = (0..1000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect::<Vec<u8>>();
+        let noisy = BytesEntry::new(noise.clone(), BytesCompression::Lz4);
+        assert!(matches!(noisy.stored, StoredBytes::Shared { .. }));
+        assert_eq!(noisy.cost(), 1000);
+    }
+}
@@ -0,0 +1,136 @@
+//! A [CacheGroup] is a budget shared by several [AssetCache](crate::AssetCache)s, which may have different [Vfs] and
+//! [Decoder] types.
+//!
+//! The group can't name the types of its members, so each cache registers its tiers as a type-erased [GroupMember].
+//! Since every [CostBasedLru] stamps its entries from the same process-wide recency clock, the group can evict the
+//! least recently used entry across all of them.
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::*;
+
+/// The part of a cache which a [CacheGroup] needs in order to evict from it.
+pub(crate) trait GroupMember: Send + Sync {
+    /// Total cost of everything in the member.
+    fn cost(&self) -> u64;
+
+    /// The recency stamp of the member's least recently used entry, if it has any.
+    fn lru_stamp(&self) -> Option<u64>;
+
+    /// Evict the least recently used entry, returning its cost.
+    fn evict_lru(&self) -> Option<u64>;
+}
+
+impl<K: CacheKey + ?Sized, O: Send + Sync> GroupMember for Tiers<K, O> {
+    fn cost(&self) -> u64 {
+        self.bytes.lock().unwrap().current_cost() + self.decoded.lock().unwrap().current_cost()
+    }
+
+    fn lru_stamp(&self) -> Option<u64> {
+        let bytes = self.bytes.lock().unwrap().lru_stamp();
+        let decoded = self.decoded.lock().unwrap().lru_stamp();
+        bytes.into_iter().chain(decoded).min()
+    }
+
+    fn evict_lru(&self) -> Option<u64> {
+        let mut bytes = self.bytes.lock().unwrap();
+        let mut decoded = self.decoded.lock().unwrap();
+        let before = bytes.current_cost() + decoded.current_cost();
+        match (bytes.lru_stamp(), decoded.lru_stamp()) {
+            (None, None) => return None,
+            (Some(b), Some(d)) if d < b => {
+                decoded.pop_lru();
+            }
+            (Some(_), _) => {
+                bytes.pop_lru();
+            }
+            (None, Some(_)) => {
+                decoded.pop_lru();
+            }
+        }
+        Some(before - bytes.current_cost() - decoded.current_cost())
+    }
+}
+
+struct GroupInner {
+    total_cost: u64,
+    members: Mutex<Vec<Weak<dyn GroupMember>>>,
+}
+
+/// A total budget shared by several [AssetCache](crate::AssetCache)s.
+///
+/// Register caches with [AssetCache::with_group](crate::AssetCache::with_group).  Whenever a member caches something
+/// and the group is over its total cost, the least recently used entries across all members are evicted, whichever
+/// cache and tier they are in.  Members keep their own budgets as well, so to let the group decide, give them
+/// generous ones.
+///
+/// Clones refer to the same group.  Members are held weakly, so dropping a cache removes it from the group.
+#[derive(Clone)]
+pub struct CacheGroup {
+    inner: Arc<GroupInner>,
+}
+
+impl CacheGroup {
+    pub fn new(total_cost: u64) -> CacheGroup {
+        CacheGroup {
+            inner: Arc::new(GroupInner {
+                total_cost,
+                members: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    pub fn total_cost(&self) -> u64 {
+        self.inner.total_cost
+    }
+
+    /// The total cost of every member.
+    pub fn current_cost(&self) -> u64 {
+        self.inner
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|m| m.upgrade())
+            .map(|m| m.cost())
+            .sum()
+    }
+
+    pub(crate) fn register(&self, member: Weak<dyn GroupMember>) {
+        self.inner.members.lock().unwrap().push(member);
+    }
+
+    /// Evict across the members until the group is back under budget.
+    ///
+    /// Must be called without holding the lock of any member's tier.
+    pub(crate) fn enforce(&self) {
+        let mut members = self.inner.members.lock().unwrap();
+        members.retain(|m| m.strong_count() > 0);
+        let live = members
+            .iter()
+            .filter_map(|m| m.upgrade())
+            .collect::<Vec<_>>();
+        // Release the list, so that caches can register while we evict.
+        drop(members);
+
+        let mut current: u64 = live.iter().map(|m| m.cost()).sum();
+        while current > self.inner.total_cost {
+            let oldest = live
+                .iter()
+                .filter_map(|m| Some((m.lru_stamp()?, m)))
+                .min_by_key(|(stamp, _)| *stamp);
+            let freed = match oldest.and_then(|(_, m)| m.evict_lru()) {
+                Some(f) => f,
+                None => break,
+            };
+            current = current.saturating_sub(freed);
+        }
+    }
+}
+
+impl std::fmt::Debug for CacheGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheGroup")
+            .field("total_cost", &self.inner.total_cost)
+            .finish()
+    }
+}
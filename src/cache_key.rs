@@ -0,0 +1,204 @@
+//! The [CacheKey] trait, for the types an [AssetCache](crate::AssetCache) can be keyed by.
+use std::borrow::{Borrow, Cow};
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::KeyNormalizer;
+
+/// A type which can key an [AssetCache](crate::AssetCache).
+///
+/// Keys are `str` by default.  Integers work too, as does any `Hash + Eq + Clone` type which implements [PlainKey],
+/// such as interned ids or typed structs, and [ParamKey] adds decode parameters to a path.  Lookups only need a
+/// reference to the key; the cache keeps its own copies behind `Arc`s, so a key is only copied when something is
+/// inserted.
+///
+/// Each key has a source, which is what the [Vfs](crate::Vfs) opens and the bytes tier is keyed by, and parameters,
+/// which are passed to the decoder by [DecodeWith](crate::DecodeWith).  For most keys the source is the key itself and
+/// there are no parameters.
+///
+/// Only `str` keys and the paths of [ParamKey]s are normalized by a [KeyNormalizer], and some features which name keys
+/// by string, such as the disk tier and [AssetCache::remove_prefix](crate::AssetCache::remove_prefix), are only
+/// available for `str` keys.
+pub trait CacheKey: Hash + Eq + Send + Sync + 'static {
+    /// The owned form of the key, which the cache hands back when listing keys.
+    type Owned: Borrow<Self> + Clone + Hash + Eq + Send + Sync + 'static;
+
+    /// What the [Vfs](crate::Vfs) opens for this key.
+    type Source: CacheKey + ?Sized;
+
+    /// The parameters the decoder receives for this key.
+    type Params;
+
+    fn source(&self) -> &Self::Source;
+
+    fn params(&self) -> &Self::Params;
+
+    /// View a source as a key, if sources are keys in their own right.  The default is `None`.
+    ///
+    /// Bulk removals use this to decide whether to keep bytes in the bytes tier.
+    fn source_as_key(source: &Self::Source) -> Option<&Self> {
+        let _ = source;
+        None
+    }
+
+    fn to_owned_key(&self) -> Self::Owned;
+
+    fn to_shared(&self) -> Arc<Self>;
+
+    /// Normalize the key, returning `None` if it is already normal.
+    ///
+    /// The default never changes the key.
+    fn normalize_with(&self, normalizer: &dyn KeyNormalizer) -> Option<Self::Owned> {
+        let _ = normalizer;
+        None
+    }
+
+    /// The key as a string, for features which need one.  The default is `None`.
+    fn as_str_key(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl CacheKey for str {
+    type Owned = String;
+    type Source = str;
+    type Params = ();
+
+    fn source(&self) -> &str {
+        self
+    }
+
+    fn params(&self) -> &() {
+        &()
+    }
+
+    fn source_as_key(source: &str) -> Option<&str> {
+        Some(source)
+    }
+
+    fn to_owned_key(&self) -> String {
+        self.to_string()
+    }
+
+    fn to_shared(&self) -> Arc<str> {
+        self.into()
+    }
+
+    fn normalize_with(&self, normalizer: &dyn KeyNormalizer) -> Option<String> {
+        match normalizer.normalize(self) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(x) => Some(x),
+        }
+    }
+
+    fn as_str_key(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+/// A key which is its own source and has no parameters.
+///
+/// Implementing this, which needs no methods, makes a type a [CacheKey].
+pub trait PlainKey: Hash + Eq + Clone + Send + Sync + 'static {}
+
+macro_rules! plain_keys {
+    ($($t:ty),*) => {
+        $(impl PlainKey for $t {})*
+    };
+}
+
+plain_keys!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, char, bool);
+
+impl<T: PlainKey> CacheKey for T {
+    type Owned = T;
+    type Source = T;
+    type Params = ();
+
+    fn source(&self) -> &T {
+        self
+    }
+
+    fn params(&self) -> &() {
+        &()
+    }
+
+    fn source_as_key(source: &T) -> Option<&T> {
+        Some(source)
+    }
+
+    fn to_owned_key(&self) -> T {
+        self.clone()
+    }
+
+    fn to_shared(&self) -> Arc<T> {
+        Arc::new(self.clone())
+    }
+}
+
+/// A key made of a [Vfs](crate::Vfs) path and parameters for decoding it, such as a mip level or locale.
+///
+/// Every set of parameters for a path shares one entry in the bytes tier, and has its own entry in the decoded tier.
+/// The decoder must implement [DecodeWith](crate::DecodeWith) for the parameters.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ParamKey<P> {
+    pub path: String,
+    pub params: P,
+}
+
+impl<P> ParamKey<P> {
+    pub fn new(path: impl Into<String>, params: P) -> ParamKey<P> {
+        ParamKey {
+            path: path.into(),
+            params,
+        }
+    }
+}
+
+impl<P: Hash + Eq + Clone + Send + Sync + 'static> CacheKey for ParamKey<P> {
+    type Owned = ParamKey<P>;
+    type Source = str;
+    type Params = P;
+
+    fn source(&self) -> &str {
+        &self.path
+    }
+
+    fn params(&self) -> &P {
+        &self.params
+    }
+
+    fn to_owned_key(&self) -> ParamKey<P> {
+        self.clone()
+    }
+
+    fn to_shared(&self) -> Arc<ParamKey<P>> {
+        Arc::new(self.clone())
+    }
+
+    fn normalize_with(&self, normalizer: &dyn KeyNormalizer) -> Option<ParamKey<P>> {
+        match normalizer.normalize(&self.path) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(path) => Some(ParamKey {
+                path,
+                params: self.params.clone(),
+            }),
+        }
+    }
+}
+
+/// A key which has been through [CacheKey::normalize_with], borrowing the original if it was already normal.
+pub(crate) enum NormalizedKey<'a, K: CacheKey + ?Sized> {
+    Borrowed(&'a K),
+    Owned(K::Owned),
+}
+
+impl<'a, K: CacheKey + ?Sized> std::ops::Deref for NormalizedKey<'a, K> {
+    type Target = K;
+
+    fn deref(&self) -> &K {
+        match self {
+            NormalizedKey::Borrowed(k) => k,
+            NormalizedKey::Owned(k) => k.borrow(),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! Opening paths under a root directory without letting symlinks escape it.
+//!
+//! On Unix, this walks the path one component at a time with `openat` and `O_NOFOLLOW`, reading any symlinks it finds
+//! and splicing their targets back into the walk, so that a symlink may point anywhere as long as the place it points
+//! is still inside the root.  Absolute targets count if they are under the root as given or as canonicalized, and are
+//! then walked from the root.  Because every step is relative to a directory we already hold open, renaming things
+//! around us can't trick us into leaving the root.  Directories are listed through the descriptor the walk ends at, so
+//! the same holds for listing.
+//!
+//! Elsewhere we fall back to canonicalizing the path and checking the result, which follows the same rules but is
+//! subject to races with concurrent modification of the directory tree.
+use std::fs::{File, Metadata};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path};
+
+/// The error behind the [ErrorKind::PermissionDenied] error returned when a path would escape the root of a
+/// [FilesystemVfs](crate::FilesystemVfs) in [Confinement::Strict](crate::Confinement::Strict) mode.
+///
+/// Use [is_root_escape] to check for it.
+#[derive(Debug, thiserror::Error)]
+#[error("path escapes the vfs root directory")]
+pub struct RootEscapeError;
+
+/// Returns true if this error was produced because a path tried to escape the root of a
+/// [FilesystemVfs](crate::FilesystemVfs).
+pub fn is_root_escape(error: &Error) -> bool {
+    error.kind() == ErrorKind::PermissionDenied
+        && error
+            .get_ref()
+            .map(|e| e.is::<RootEscapeError>())
+            .unwrap_or(false)
+}
+
+fn escape_error() -> Error {
+    Error::new(ErrorKind::PermissionDenied, RootEscapeError)
+}
+
+/// Strip any leading root from a requested key, so that `/a` means the same as `a`, as it does in
+/// [Confinement::Logical](crate::Confinement::Logical) mode.
+fn strip_root(path: &Path) -> &Path {
+    let mut components = path.components();
+    while let Some(Component::Prefix(_) | Component::RootDir) = components.clone().next() {
+        components.next();
+    }
+    components.as_path()
+}
+
+/// What a directory entry is, without following links.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum EntryKind {
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl From<std::fs::FileType> for EntryKind {
+    fn from(file_type: std::fs::FileType) -> EntryKind {
+        if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::Other
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::ffi::{CString, OsStr, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+    /// Same limit as Linux uses for symlink resolution.
+    const MAX_SYMLINK_HOPS: usize = 40;
+
+    enum Step {
+        /// Go back to the root, for absolute symlink targets.
+        Root,
+        Parent,
+        Name(OsString),
+    }
+
+    /// Push the components of a relative path onto the front of the queue, preserving their order.
+    fn push_front_components(path: &Path, queue: &mut VecDeque<Step>) -> Result<()> {
+        let mut steps = vec![];
+        for c in path.components() {
+            match c {
+                Component::Prefix(_) | Component::RootDir => return Err(escape_error()),
+                Component::CurDir => {}
+                Component::ParentDir => steps.push(Step::Parent),
+                Component::Normal(n) => steps.push(Step::Name(n.to_os_string())),
+            }
+        }
+
+        for s in steps.into_iter().rev() {
+            queue.push_front(s);
+        }
+        Ok(())
+    }
+
+    fn to_cstring(name: &OsStr) -> Result<CString> {
+        CString::new(name.as_bytes())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains a NUL byte"))
+    }
+
+    /// Push the components of an absolute symlink target, if it is under the root.
+    fn push_front_absolute(root: &Path, target: &Path, queue: &mut VecDeque<Step>) -> Result<()> {
+        let canonical_root = root.canonicalize()?;
+        let rest = target
+            .strip_prefix(root)
+            .or_else(|_| target.strip_prefix(&canonical_root))
+            .map_err(|_| escape_error())?;
+        push_front_components(rest, queue)?;
+        queue.push_front(Step::Root);
+        Ok(())
+    }
+
+    fn entry_kind(dir: RawFd, name: &CString) -> Result<EntryKind> {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        let res =
+            unsafe { libc::fstatat(dir, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) };
+        if res != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(match stat.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => EntryKind::Dir,
+            libc::S_IFLNK => EntryKind::Symlink,
+            _ => EntryKind::Other,
+        })
+    }
+
+    fn read_link(dir: RawFd, name: &CString) -> Result<OsString> {
+        let mut buf = vec![0u8; 256];
+        loop {
+            let len = unsafe {
+                libc::readlinkat(
+                    dir,
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            // If the buffer was filled, the target may have been truncated.
+            let len = len as usize;
+            if len < buf.len() {
+                buf.truncate(len);
+                return Ok(OsString::from_vec(buf));
+            }
+            let new_len = buf.len() * 2;
+            buf.resize(new_len, 0);
+        }
+    }
+
+    fn open_at(dir: RawFd, name: &CString, directory: bool) -> Result<File> {
+        let mut flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+        if directory {
+            flags |= libc::O_DIRECTORY;
+        }
+        let fd = unsafe { libc::openat(dir, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub(crate) fn open_confined(root: &Path, path: &Path) -> Result<File> {
+        let mut queue = VecDeque::new();
+        push_front_components(strip_root(path), &mut queue)?;
+
+        // The directories we've descended through, so that `..` can go back up without looking at the filesystem.
+        let mut stack = vec![File::open(root)?];
+        let mut hops = 0;
+
+        while let Some(step) = queue.pop_front() {
+            let name = match step {
+                Step::Root => {
+                    stack.truncate(1);
+                    continue;
+                }
+                Step::Parent => {
+                    if stack.len() == 1 {
+                        return Err(escape_error());
+                    }
+                    stack.pop();
+                    continue;
+                }
+                Step::Name(n) => to_cstring(&n)?,
+            };
+
+            let dir = stack.last().expect("Always contains the root").as_raw_fd();
+            if entry_kind(dir, &name)? == EntryKind::Symlink {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = read_link(dir, &name)?;
+                let target = Path::new(&target);
+                if target.has_root() {
+                    push_front_absolute(root, target, &mut queue)?;
+                } else {
+                    push_front_components(target, &mut queue)?;
+                }
+                continue;
+            }
+
+            // If this was swapped for a symlink since we checked, `O_NOFOLLOW` makes the open fail.
+            stack.push(open_at(dir, &name, !queue.is_empty())?);
+        }
+
+        Ok(stack.pop().expect("Always contains the root"))
+    }
+
+    pub(crate) fn metadata_confined(root: &Path, path: &Path) -> Result<Metadata> {
+        open_confined(root, path)?.metadata()
+    }
+
+    /// Closes a directory stream on drop.
+    struct DirStream(*mut libc::DIR);
+
+    impl Drop for DirStream {
+        fn drop(&mut self) {
+            unsafe { libc::closedir(self.0) };
+        }
+    }
+
+    pub(crate) fn read_dir_confined(
+        root: &Path,
+        path: &Path,
+    ) -> Result<Vec<(OsString, EntryKind)>> {
+        let dir = open_confined(root, path)?;
+        // The stream takes ownership of the descriptor it is given, so give it a copy.
+        let fd = unsafe { libc::dup(dir.as_raw_fd()) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let err = Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let stream = DirStream(stream);
+
+        let mut out = vec![];
+        loop {
+            let entry = unsafe { libc::readdir(stream.0) };
+            if entry.is_null() {
+                break;
+            }
+            let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+            if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                continue;
+            }
+            let kind = entry_kind(dir.as_raw_fd(), &name.to_owned())?;
+            out.push((OsStr::from_bytes(name.to_bytes()).to_os_string(), kind));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn resolve(root: &Path, path: &Path) -> Result<PathBuf> {
+        let path = strip_root(path);
+        let root = root.canonicalize()?;
+        let resolved = root.join(path).canonicalize()?;
+        if !resolved.starts_with(&root) {
+            return Err(escape_error());
+        }
+        Ok(resolved)
+    }
+
+    pub(crate) fn open_confined(root: &Path, path: &Path) -> Result<File> {
+        File::open(resolve(root, path)?)
+    }
+
+    pub(crate) fn metadata_confined(root: &Path, path: &Path) -> Result<Metadata> {
+        std::fs::metadata(resolve(root, path)?)
+    }
+
+    pub(crate) fn read_dir_confined(
+        root: &Path,
+        path: &Path,
+    ) -> Result<Vec<(std::ffi::OsString, EntryKind)>> {
+        let mut out = vec![];
+        for entry in std::fs::read_dir(resolve(root, path)?)? {
+            let entry = entry?;
+            out.push((entry.file_name(), entry.file_type()?.into()));
+        }
+        Ok(out)
+    }
+}
+
+pub(crate) use imp::*;
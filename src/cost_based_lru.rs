@@ -4,19 +4,37 @@
 //! auxiliary hash-based index.
 //!
 //! The keys may not die immediately on eviction; only the value should be large.
+//!
+//! Entries are also stamped from a process-wide recency clock whenever they are used, so that the least recently used
+//! entries of different caches can be compared.  See [CostBasedLru::lru_stamp].
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use ahash::RandomState;
 
+static RECENCY_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// Get a new stamp from the recency clock.
+fn tick() -> u64 {
+    RECENCY_CLOCK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The current value of the recency clock, which is newer than every stamp handed out so far.
+pub(crate) fn recency_now() -> u64 {
+    RECENCY_CLOCK.load(Ordering::Relaxed)
+}
+
 struct OccupiedEntry<K: ?Sized, V> {
     key: Arc<K>,
     item: Arc<V>,
     prev: Option<usize>,
     next: Option<usize>,
     cost: u64,
+    /// When this entry was last used.
+    stamp: u64,
 }
 
 struct EmptyEntry {
@@ -62,6 +80,8 @@ pub struct CostBasedLru<K: ?Sized + std::hash::Hash + Eq, V> {
     index: HashMap<Arc<K>, usize, RandomState>,
     // At what cost do we start evicting?
     max_cost: u64,
+    /// Once we start evicting, how far down do we go?
+    low_watermark: u64,
     entries_head: Option<usize>,
     entries_tail: Option<usize>,
     empty_head: Option<usize>,
@@ -71,10 +91,25 @@ pub struct CostBasedLru<K: ?Sized + std::hash::Hash + Eq, V> {
 
 impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
     pub fn new(max_cost: u64) -> CostBasedLru<K, V> {
+        CostBasedLru::with_watermarks(max_cost, max_cost)
+    }
+
+    /// Make a cache which, once its cost goes over `high`, evicts until it is at or below `low` rather than just
+    /// below `high`.
+    ///
+    /// This trades some capacity for evicting in batches, rather than one item on nearly every insert once full.  When
+    /// `low` is below `high`, an insert never evicts the entry it inserted, so the cost can stay above `high` until the
+    /// next insert if that entry alone costs more than `low`.  Panics if `low > high`.
+    pub fn with_watermarks(high: u64, low: u64) -> CostBasedLru<K, V> {
+        assert!(
+            low <= high,
+            "The low watermark must not be above the high one"
+        );
         CostBasedLru {
             entries: Default::default(),
             index: Default::default(),
-            max_cost,
+            max_cost: high,
+            low_watermark: low,
             entries_head: None,
             entries_tail: None,
             empty_head: None,
@@ -115,7 +150,9 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
     /// Given the index of an occupied entry, make it the most recent item.
     fn make_most_recent(&mut self, index: usize) {
         self.unlink_index(index);
-        self.entries[index].as_occupied_mut().next = self.entries_head;
+        let entry = self.entries[index].as_occupied_mut();
+        entry.prev = None;
+        entry.next = self.entries_head;
         if let Some(i) = self.entries_head {
             self.entries[i].as_occupied_mut().prev = Some(index);
         }
@@ -127,14 +164,35 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
         }
     }
 
-    pub fn get<Q: ?Sized>(&mut self, key: &Q) -> Option<Arc<V>>
+    pub fn get<Q>(&mut self, key: &Q) -> Option<Arc<V>>
     where
         Arc<K>: Borrow<Q>,
-        Q: std::hash::Hash + Eq,
+        Q: ?Sized + std::hash::Hash + Eq,
     {
         let ind = *self.index.get(key)?;
         self.make_most_recent(ind);
-        Some(self.entries[ind].as_occupied_mut().item.clone())
+        let entry = self.entries[ind].as_occupied_mut();
+        entry.stamp = tick();
+        Some(entry.item.clone())
+    }
+
+    /// Whether the key is present, without counting as a use.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Arc<K>: Borrow<Q>,
+        Q: ?Sized + std::hash::Hash + Eq,
+    {
+        self.index.contains_key(key)
+    }
+
+    /// The cost of a key, if present, without counting as a use.
+    pub fn peek_cost<Q>(&self, key: &Q) -> Option<u64>
+    where
+        Arc<K>: Borrow<Q>,
+        Q: ?Sized + std::hash::Hash + Eq,
+    {
+        let ind = *self.index.get(key)?;
+        Some(self.entries[ind].as_occupied().cost)
     }
 
     /// Make a specific index of the map become empty.
@@ -157,10 +215,10 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
         }
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<Arc<V>>
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<Arc<V>>
     where
         Arc<K>: Borrow<Q>,
-        Q: std::hash::Hash + Eq,
+        Q: ?Sized + std::hash::Hash + Eq,
     {
         let ind = self.index.remove(key)?;
         let old = self.become_empty(ind);
@@ -181,16 +239,22 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
 
     /// Add an entry to the cache.  Return the old entry if this key was already present.
     pub fn insert(&mut self, key: Arc<K>, value: V, cost: u64) -> Option<Arc<V>> {
+        self.insert_arc(key, Arc::new(value), cost)
+    }
+
+    /// Like [CostBasedLru::insert], for a value which is already shared.
+    pub fn insert_arc(&mut self, key: Arc<K>, value: Arc<V>, cost: u64) -> Option<Arc<V>> {
         let ret = self.remove(&key);
         let ind = self.find_empty();
         let old_head = self.entries_head;
 
         self.entries[ind] = CacheEntry::Occupied(OccupiedEntry {
             key: key.clone(),
-            item: Arc::new(value),
+            item: value,
             prev: None,
             next: self.entries_head,
             cost,
+            stamp: tick(),
         });
         self.entries_head = Some(ind);
         self.index.insert(key, ind);
@@ -210,9 +274,28 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
         ret
     }
 
-    /// Run a cache eviction if required.
+    /// Run a cache eviction if required.  With a low watermark, this spares the most recently used entry.
     fn maybe_evict(&mut self) {
-        while self.current_cost > self.max_cost {
+        if self.current_cost <= self.max_cost {
+            return;
+        }
+
+        if self.low_watermark == self.max_cost {
+            self.trim(self.max_cost);
+            return;
+        }
+
+        while self.current_cost > self.low_watermark && self.entries_tail != self.entries_head {
+            let tail = self
+                .entries_tail
+                .expect("Not enough entries to explain cost");
+            self.become_empty(tail);
+        }
+    }
+
+    /// Evict least recently used entries until the cost is at or below `target`.
+    pub fn trim(&mut self, target: u64) {
+        while self.current_cost > target {
             let cur = match self.entries_tail {
                 Some(t) => t,
                 None => panic!("Not enough entries to explain cost"),
@@ -222,6 +305,27 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
         }
     }
 
+    /// Remove and return the least recently used entry.
+    pub fn pop_lru(&mut self) -> Option<(Arc<K>, Arc<V>)> {
+        let tail = self.entries_tail?;
+        let key = self.entries[tail].as_occupied().key.clone();
+        let item = self.become_empty(tail);
+        Some((key, item))
+    }
+
+    /// The recency stamp of the least recently used entry, or `None` if the cache is empty.
+    ///
+    /// Stamps come from a process-wide clock which ticks every time any entry of any cache is used, so they can be
+    /// compared between caches: smaller is older.
+    pub fn lru_stamp(&self) -> Option<u64> {
+        Some(self.entries[self.entries_tail?].as_occupied().stamp)
+    }
+
+    /// The total cost of everything currently in the cache.
+    pub fn current_cost(&self) -> u64 {
+        self.current_cost
+    }
+
     /// Iterator visiting entries in most-recently-used order.
     pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
         let mut ind = self.entries_head;
@@ -233,8 +337,34 @@ impl<K: ?Sized + Hash + Eq, V> CostBasedLru<K, V> {
         })
     }
 
+    /// Remove every entry for which `keep` returns false.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        let doomed = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| match e {
+                CacheEntry::Occupied(o) if !keep(&o.key, &o.item) => Some(i),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for i in doomed {
+            self.become_empty(i);
+        }
+    }
+
+    /// Remove and return every entry, least recently used first.
+    pub fn drain(&mut self) -> impl Iterator<Item = (Arc<K>, Arc<V>)> {
+        let mut out = vec![];
+        while let Some(e) = self.pop_lru() {
+            out.push(e);
+        }
+        self.clear();
+        out.into_iter()
+    }
+
     pub fn clear(&mut self) {
-        *self = Self::new(self.max_cost);
+        *self = Self::with_watermarks(self.max_cost, self.low_watermark);
     }
 }
 
@@ -279,7 +409,7 @@ mod tests {
             commands in prop::collection::vec(cache_command_strat(0..100, 0..10000), 0..10000)
         ) {
             let mut known_good = LruCache::<u64, u64>::new(bound as usize);
-            let mut ours = CostBasedLru::<u64, u64>::new(bound as u64);
+            let mut ours = CostBasedLru::<u64, u64>::new(bound);
 
             for c in commands {
                 use CacheCommand::*;
@@ -302,6 +432,28 @@ mod tests {
         }
     }
 
+    // Moving an entry from the middle or back to the front used to leave its old predecessor as its prev, which broke
+    // the tail once everything else was gone.
+    #[test]
+    fn test_move_to_front_then_empty() {
+        let mut cache = CostBasedLru::<u64, u64>::new(3);
+        for i in 1..=3 {
+            cache.insert(Arc::new(i), i, 1);
+        }
+        cache.get(&1);
+        cache.remove(&3);
+        cache.remove(&2);
+        cache.remove(&1);
+        assert_eq!(cache.current_cost(), 0);
+
+        for i in 4..=7 {
+            cache.insert(Arc::new(i), i, 1);
+        }
+        let keys = cache.iter().map(|x| *x.0).collect::<Vec<u64>>();
+        assert_eq!(keys, vec![7, 6, 5]);
+        assert_eq!(cache.current_cost(), 3);
+    }
+
     // We know everything else works, including complex linked lists for eviction, but let's still check what happens
     // without a cost of zero.
     #[test]
@@ -318,5 +470,75 @@ mod tests {
             .map(|x| (*x.0, *x.1))
             .collect::<Vec<(u64, u64)>>();
         assert_eq!(state, vec![(5, 5), (4, 4)]);
+
+        let mut other = CostBasedLru::<u64, u64>::new(100);
+        other.insert(Arc::new(1), 1, 1);
+        assert!(cache.lru_stamp().unwrap() < other.lru_stamp().unwrap());
+        cache.get(&4);
+        cache.get(&5);
+        assert!(cache.lru_stamp().unwrap() > other.lru_stamp().unwrap());
+
+        assert_eq!(cache.pop_lru().map(|x| (*x.0, *x.1)), Some((4, 4)));
+        assert_eq!(cache.current_cost(), 5);
+        assert_eq!(cache.pop_lru().map(|x| (*x.0, *x.1)), Some((5, 5)));
+        assert!(cache.pop_lru().is_none());
+        assert_eq!(cache.current_cost(), 0);
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let mut cache = CostBasedLru::<u64, u64>::with_watermarks(10, 5);
+        for i in 1..=10 {
+            cache.insert(Arc::new(i), i, 1);
+        }
+        assert_eq!(cache.current_cost(), 10);
+
+        // Crossing the high watermark evicts down to the low one.
+        cache.insert(Arc::new(11), 11, 1);
+        assert_eq!(cache.current_cost(), 5);
+        let keys = cache.iter().map(|x| *x.0).collect::<Vec<u64>>();
+        assert_eq!(keys, vec![11, 10, 9, 8, 7]);
+
+        cache.trim(2);
+        let keys = cache.iter().map(|x| *x.0).collect::<Vec<u64>>();
+        assert_eq!(keys, vec![11, 10]);
+
+        // An entry costing more than the low watermark evicts everything else, but not itself.
+        let arc = cache.insert_arc(Arc::new(12), Arc::new(12), 9);
+        assert!(arc.is_none());
+        assert_eq!(cache.iter().map(|x| *x.0).collect::<Vec<u64>>(), vec![12]);
+        assert_eq!(cache.current_cost(), 9);
+    }
+
+    #[test]
+    fn test_oversized_entry() {
+        // Without a low watermark, an entry costing more than the maximum evicts everything, including itself.
+        let mut cache = CostBasedLru::<u64, u64>::new(10);
+        cache.insert(Arc::new(1), 1, 5);
+        cache.insert(Arc::new(2), 2, 11);
+        assert_eq!(cache.current_cost(), 0);
+        assert!(cache.iter().next().is_none());
+        assert!(cache.get(&2).is_none());
+    }
+
+    #[test]
+    fn test_retain_and_drain() {
+        let mut cache = CostBasedLru::<u64, u64>::new(100);
+        for i in 1..=6 {
+            cache.insert(Arc::new(i), i * 10, i);
+        }
+
+        cache.retain(|k, v| k % 2 == 0 && *v != 40);
+        assert_eq!(cache.current_cost(), 8);
+        let keys = cache.iter().map(|x| *x.0).collect::<Vec<u64>>();
+        assert_eq!(keys, vec![6, 2]);
+
+        // The list is still intact after removing from the middle.
+        cache.insert(Arc::new(7), 70, 1);
+        cache.get(&2);
+        let drained = cache.drain().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        assert_eq!(drained, vec![(6, 60), (7, 70), (2, 20)]);
+        assert_eq!(cache.current_cost(), 0);
+        assert!(cache.pop_lru().is_none());
     }
 }
@@ -0,0 +1,529 @@
+//! A [Vfs] wrapper which transparently decompresses `.gz` and `.zst` files.
+//!
+//! Each format is behind a cargo feature of the same name as the format: `gzip` and `zstd`.
+//!
+//! Decompressed streams can't seek, but [VfsReader] requires it.  We handle this by streaming for as long as the
+//! consumer only reads forward, and falling back to decompressing the whole thing into memory the first time anything
+//! needs to seek backward or relative to the end.
+//!
+//! The decompressed size isn't known for certain until then.  gzip records it modulo 2^32 and only for the last member,
+//! and Zstandard only per frame and optionally, so what the format records is reported by [VfsReader::get_size] as a
+//! hint.  The [AssetCache] goes by what it actually reads when deciding whether to keep the bytes.
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom};
+
+use crate::*;
+
+/// A compression format understood by [DecompressingVfs].
+///
+/// More formats may be added, so matches on this need a wildcard arm.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// gzip, for files ending in `.gz`.
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// Zstandard, for files ending in `.zst`.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// All formats enabled in this build, in the order [DecompressingVfs] tries them by default.
+    pub const ENABLED: &'static [Compression] = &[
+        #[cfg(feature = "gzip")]
+        Compression::Gzip,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+    ];
+
+    /// The extension, including the dot, that files in this format have.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => ".gz",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Read the decompressed size the format records, if any, leaving the reader at the start.
+    ///
+    /// Failing to find one isn't an error, since the stream may still decompress fine.
+    fn size_hint<R: Read + Seek>(&self, reader: &mut R) -> Result<Option<u64>> {
+        let hint = match self {
+            // The last 4 bytes of a gzip member are the size modulo 2^32.
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => (|| {
+                let mut buf = [0u8; 4];
+                reader.seek(SeekFrom::End(-4))?;
+                reader.read_exact(&mut buf)?;
+                Ok::<_, Error>(u32::from_le_bytes(buf) as u64)
+            })()
+            .ok(),
+            // Zstandard frame headers optionally hold the content size, and are at most 18 bytes.
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut buf = vec![];
+                reader.by_ref().take(18).read_to_end(&mut buf)?;
+                zstd::zstd_safe::get_frame_content_size(&buf).ok().flatten()
+            }
+        };
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(hint)
+    }
+
+    fn wrap<R: Read>(&self, reader: R) -> Result<Stream<R>> {
+        Ok(match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Stream::Gzip(flate2::read::MultiGzDecoder::new(reader)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Stream::Zstd(zstd::stream::read::Decoder::new(reader)?),
+        })
+    }
+}
+
+/// A decompressing stream, which we can get the compressed reader back out of in order to start over.
+enum Stream<R: Read> {
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::read::MultiGzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, std::io::BufReader<R>>),
+}
+
+impl<R: Read> Stream<R> {
+    fn into_source(self) -> R {
+        match self {
+            #[cfg(feature = "gzip")]
+            Stream::Gzip(x) => x.into_inner(),
+            #[cfg(feature = "zstd")]
+            Stream::Zstd(x) => x.finish().into_inner(),
+        }
+    }
+}
+
+impl<R: Read> Read for Stream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Stream::Gzip(x) => x.read(buf),
+            #[cfg(feature = "zstd")]
+            Stream::Zstd(x) => x.read(buf),
+        }
+    }
+}
+
+enum ReaderState<R: Read> {
+    /// The file wasn't compressed.
+    Plain(R),
+    Streaming {
+        format: Compression,
+        stream: Stream<R>,
+        pos: u64,
+    },
+    /// We had to seek, so the whole thing is in memory.
+    Buffered(Cursor<Vec<u8>>),
+    /// Switching to buffered failed part way through.
+    Poisoned,
+}
+
+/// The reader for a [DecompressingVfs].
+pub struct DecompressingReader<R: Read> {
+    state: ReaderState<R>,
+    /// The decompressed size the format recorded, which may be wrong.
+    size_hint: Option<u64>,
+    /// The content hash of the underlying reader.  The compressed content identifies the decompressed content just as
+    /// well.
+    content_hash: Option<u64>,
+}
+
+fn poisoned() -> Error {
+    Error::other("Reader is unusable after failing to buffer the decompressed stream")
+}
+
+impl<R: VfsReader> DecompressingReader<R> {
+    fn plain(reader: R) -> DecompressingReader<R> {
+        DecompressingReader {
+            content_hash: reader.content_hash(),
+            state: ReaderState::Plain(reader),
+            size_hint: None,
+        }
+    }
+
+    fn decompressing(mut reader: R, format: Compression) -> Result<DecompressingReader<R>> {
+        Ok(DecompressingReader {
+            size_hint: format.size_hint(&mut reader)?,
+            content_hash: reader.content_hash(),
+            state: ReaderState::Streaming {
+                format,
+                stream: format.wrap(reader)?,
+                pos: 0,
+            },
+        })
+    }
+
+    /// Switch from streaming to buffered, preserving the position.
+    fn buffer(&mut self) -> Result<()> {
+        let (format, stream, pos) = match std::mem::replace(&mut self.state, ReaderState::Poisoned)
+        {
+            ReaderState::Streaming {
+                format,
+                stream,
+                pos,
+            } => (format, stream, pos),
+            x => {
+                self.state = x;
+                return Ok(());
+            }
+        };
+
+        let mut source = stream.into_source();
+        source.seek(SeekFrom::Start(0))?;
+        let mut data = vec![];
+        format.wrap(source)?.read_to_end(&mut data)?;
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(pos);
+        self.state = ReaderState::Buffered(cursor);
+        Ok(())
+    }
+}
+
+impl<R: VfsReader> Read for DecompressingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match &mut self.state {
+            ReaderState::Plain(x) => x.read(buf),
+            ReaderState::Streaming { stream, pos, .. } => {
+                let got = stream.read(buf)?;
+                *pos += got as u64;
+                Ok(got)
+            }
+            ReaderState::Buffered(x) => x.read(buf),
+            ReaderState::Poisoned => Err(poisoned()),
+        }
+    }
+}
+
+impl<R: VfsReader> Seek for DecompressingReader<R> {
+    fn seek(&mut self, seek: SeekFrom) -> Result<u64> {
+        if let ReaderState::Streaming { stream, pos, .. } = &mut self.state {
+            let target = match seek {
+                SeekFrom::Start(x) => Some(x),
+                SeekFrom::Current(x) => pos.checked_add_signed(x),
+                SeekFrom::End(_) => None,
+            };
+
+            // Going forward (including not moving at all, which is how `stream_position` works) can be done by
+            // reading and throwing the bytes away.
+            if let Some(target) = target.filter(|t| *t >= *pos) {
+                std::io::copy(&mut stream.take(target - *pos), &mut std::io::sink())?;
+                *pos = target;
+                return Ok(target);
+            }
+
+            self.buffer()?;
+        }
+
+        match &mut self.state {
+            ReaderState::Plain(x) => x.seek(seek),
+            ReaderState::Buffered(x) => x.seek(seek),
+            ReaderState::Poisoned => Err(poisoned()),
+            ReaderState::Streaming { .. } => unreachable!("We just buffered"),
+        }
+    }
+}
+
+impl<R: VfsReader> VfsReader for DecompressingReader<R> {
+    /// For compressed files, this is the size the format records until the reader has had to buffer the whole
+    /// decompressed content, and may be wrong.  If the format doesn't record one, it fails with
+    /// [ErrorKind::Unsupported].
+    fn get_size(&self) -> Result<u64> {
+        match &self.state {
+            ReaderState::Plain(x) => x.get_size(),
+            ReaderState::Buffered(x) => Ok(x.get_ref().len() as u64),
+            _ => self
+                .size_hint
+                .ok_or_else(|| Error::new(ErrorKind::Unsupported, "Decompressed size is unknown")),
+        }
+    }
+
+    fn mapped_bytes(&self) -> Option<SharedBytes> {
+        match &self.state {
+            ReaderState::Plain(x) => x.mapped_bytes(),
+            _ => None,
+        }
+    }
+
+    fn content_hash(&self) -> Option<u64> {
+        self.content_hash
+    }
+}
+
+/// A [Vfs] which looks up `key`, then `key.gz`, `key.zst`, etc., and decompresses whatever it finds.
+///
+/// Keys are always given without the compression extension, and [Vfs::list] strips it.  Uncompressed files take
+/// priority.
+pub struct DecompressingVfs<V> {
+    inner: V,
+    formats: Vec<Compression>,
+}
+
+impl<V: Vfs> DecompressingVfs<V> {
+    /// Wrap a [Vfs], trying all formats enabled in this build.
+    pub fn new(inner: V) -> DecompressingVfs<V> {
+        DecompressingVfs::with_formats(inner, Compression::ENABLED.to_vec())
+    }
+
+    /// Wrap a [Vfs], trying only the given formats in the given order.
+    pub fn with_formats(inner: V, formats: Vec<Compression>) -> DecompressingVfs<V> {
+        DecompressingVfs { inner, formats }
+    }
+
+    pub fn inner(&self) -> &V {
+        &self.inner
+    }
+
+    /// Find which key in the inner [Vfs] backs this one, and in what format.
+    fn find<T>(
+        &self,
+        key: &str,
+        mut op: impl FnMut(&str) -> Result<T>,
+    ) -> Result<(T, Option<Compression>)> {
+        let not_found = match op(key) {
+            Ok(x) => return Ok((x, None)),
+            Err(e) if e.kind() == ErrorKind::NotFound => e,
+            Err(e) => return Err(e),
+        };
+
+        for f in self.formats.iter() {
+            match op(&format!("{}{}", key, f.extension())) {
+                Ok(x) => return Ok((x, Some(*f))),
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(not_found)
+    }
+}
+
+impl<V: Vfs> Vfs for DecompressingVfs<V> {
+    type Reader = DecompressingReader<V::Reader>;
+
+    fn open(&self, key: &str) -> Result<Self::Reader> {
+        match self.find(key, |k| self.inner.open(k))? {
+            (r, None) => Ok(DecompressingReader::plain(r)),
+            (r, Some(f)) => DecompressingReader::decompressing(r, f),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = self
+            .inner
+            .list(prefix)?
+            .into_iter()
+            .map(|mut k| {
+                if let Some(f) = self.formats.iter().find(|f| k.ends_with(f.extension())) {
+                    k.truncate(k.len() - f.extension().len());
+                }
+                k
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        let found = self.find(key, |k| {
+            if self.inner.exists(k)? {
+                Ok(())
+            } else {
+                Err(Error::from(ErrorKind::NotFound))
+            }
+        });
+        match found {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// For compressed files, the size is that of the compressed file, since the decompressed size isn't known
+    /// without decompressing.
+    fn metadata(&self, key: &str) -> Result<VfsMetadata> {
+        Ok(self.find(key, |k| self.inner.metadata(k))?.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::StringDecoder;
+
+    const CONTENT: &str = "The quick brown fox jumps over the lazy dog.";
+
+    /// A MemoryVfs holding `CONTENT` under `plain`, and compressed versions under `<format>.<ext>`.
+    fn build_vfs() -> DecompressingVfs<MemoryVfs> {
+        let vfs = MemoryVfs::new();
+        vfs.insert("plain", CONTENT.as_bytes());
+        #[cfg(feature = "gzip")]
+        {
+            use std::io::Write;
+
+            let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            enc.write_all(CONTENT.as_bytes()).unwrap();
+            vfs.insert("gzip.gz", enc.finish().unwrap());
+        }
+        #[cfg(feature = "zstd")]
+        {
+            use std::io::Write;
+
+            // The bulk API records the content size, the streaming one doesn't.
+            vfs.insert(
+                "zstd.zst",
+                zstd::bulk::compress(CONTENT.as_bytes(), 0).unwrap(),
+            );
+            let mut enc = zstd::stream::write::Encoder::new(vec![], 0).unwrap();
+            enc.write_all(CONTENT.as_bytes()).unwrap();
+            vfs.insert("zstd_unsized.zst", enc.finish().unwrap());
+        }
+        DecompressingVfs::new(vfs)
+    }
+
+    fn keys() -> Vec<&'static str> {
+        let mut keys = vec!["plain"];
+        #[cfg(feature = "gzip")]
+        keys.push("gzip");
+        #[cfg(feature = "zstd")]
+        keys.extend(["zstd", "zstd_unsized"]);
+        keys
+    }
+
+    #[test]
+    fn test_decompressing_vfs() {
+        let vfs = build_vfs();
+        for key in keys() {
+            let mut reader = vfs.open(key).unwrap();
+            if key != "zstd_unsized" {
+                assert_eq!(reader.get_size().unwrap(), CONTENT.len() as u64, "{}", key);
+            } else {
+                assert_eq!(
+                    reader.get_size().unwrap_err().kind(),
+                    ErrorKind::Unsupported,
+                    "{}",
+                    key
+                );
+            }
+
+            // Forward seeks stream.
+            reader.seek(SeekFrom::Start(4)).unwrap();
+            let mut word = [0u8; 5];
+            reader.read_exact(&mut word).unwrap();
+            assert_eq!(&word, b"quick");
+            assert_eq!(reader.stream_position().unwrap(), 9);
+
+            // Backward ones buffer.
+            reader.seek(SeekFrom::Current(-5)).unwrap();
+            let mut out = String::new();
+            reader.read_to_string(&mut out).unwrap();
+            assert_eq!(out, &CONTENT[4..], "{}", key);
+            reader.seek(SeekFrom::End(-4)).unwrap();
+            out.clear();
+            reader.read_to_string(&mut out).unwrap();
+            assert_eq!(out, "dog.");
+            // Once buffered, the size is known.
+            assert_eq!(reader.get_size().unwrap(), CONTENT.len() as u64);
+
+            assert!(vfs.exists(key).unwrap());
+        }
+
+        let mut expected = keys();
+        expected.sort();
+        assert_eq!(vfs.list("").unwrap(), expected);
+        assert!(!vfs.exists("missing").unwrap());
+        assert_eq!(
+            vfs.open("missing").map(|_| ()).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_through_cache() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(1000)
+            .max_single_object_bytes_cost(1000)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .build()
+            .unwrap();
+        let cache = AssetCache::new(build_vfs(), StringDecoder, cfg);
+        // With a size hint, the bytes are cached, so decoding again doesn't read anything.  Without one, we still
+        // decode; we just don't cache the bytes.
+        for key in keys() {
+            let mut bytes_read = 0;
+            assert_eq!(
+                &*cache.find_or_decode(key, &mut bytes_read).unwrap(),
+                CONTENT
+            );
+            assert_eq!(
+                &*cache.find_or_decode(key, &mut bytes_read).unwrap(),
+                CONTENT
+            );
+            let expected = if key == "zstd_unsized" { 2 } else { 1 };
+            assert_eq!(bytes_read, expected * CONTENT.len() as u64, "{}", key);
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_edge_cases() {
+        use std::io::Write;
+
+        // Only the last member's size is recorded, so it's only a hint.
+        let mut data = vec![];
+        for part in ["a".repeat(1000), "b".repeat(3)] {
+            let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            enc.write_all(part.as_bytes()).unwrap();
+            data.extend(enc.finish().unwrap());
+        }
+        let inner = MemoryVfs::new();
+        inner.insert("multi.gz", data);
+        // Too short to be gzip at all.
+        inner.insert("short.gz", &b"\x1f"[..]);
+        let vfs = DecompressingVfs::new(inner);
+
+        let mut reader = vfs.open("multi").unwrap();
+        assert_eq!(reader.get_size().unwrap(), 3);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out.len(), 1003);
+        assert_eq!(
+            vfs.open("short").unwrap().get_size().unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(10)
+            .max_single_object_bytes_cost(10)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .build()
+            .unwrap();
+        let cache = AssetCache::new(vfs, StringDecoder, cfg);
+        // The hint is under the limit, but what's actually read isn't, so the bytes aren't kept.
+        let mut bytes_read = 0;
+        for _ in 0..2 {
+            assert_eq!(
+                cache
+                    .find_or_decode("multi", &mut bytes_read)
+                    .unwrap()
+                    .len(),
+                1003
+            );
+        }
+        assert_eq!(bytes_read, 2006);
+        assert!(matches!(
+            cache.get("short"),
+            Err(AssetCacheError::Decoder(_))
+        ));
+    }
+}
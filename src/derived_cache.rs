@@ -0,0 +1,393 @@
+//! A [DerivedCache] memoizes things computed from the items of an [AssetCache], such as a collision mesh from a model,
+//! with the same cost-based eviction.
+//!
+//! Outputs of every [Transform] share one [CostBasedLru], keyed by the source key and the transform's id, so they are
+//! stored type-erased and downcast on the way out.  Transform ids are interned, so lookups don't copy them.  The
+//! derived cache registers with its [AssetCache] as an [InvalidationListener], so that outputs are dropped when their
+//! source is removed or replaced.
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use crate::*;
+
+/// Computes something from a decoded item, for a [DerivedCache].
+pub trait Transform<Source>: Send + Sync {
+    type Output: Send + Sync + 'static;
+    type Error: std::error::Error;
+
+    /// Distinguishes this transform's outputs from those of other transforms of the same source.
+    ///
+    /// Transforms which produce different outputs, including differently configured instances of one type, must have
+    /// different ids.
+    fn id(&self) -> &str;
+
+    fn transform(&self, source: Arc<Source>) -> Result<Self::Output, Self::Error>;
+
+    /// Estimate the cost of an output, usually the in-memory size.
+    fn estimate_cost(&self, output: &Self::Output) -> Result<u64, Self::Error>;
+}
+
+/// Told by an [AssetCache] when an item is removed or replaced, so that anything computed from it can be dropped.
+///
+/// Register with [AssetCache::add_invalidation_listener].
+pub trait InvalidationListener<K: ?Sized>: Send + Sync {
+    /// Called with the normalized key, without any of the cache's locks held.
+    fn invalidate(&self, key: &K);
+}
+
+/// An error from [DerivedCache::get].
+#[derive(Debug, thiserror::Error)]
+pub enum DerivedCacheError<DecoderError, TransformError> {
+    /// Loading the source failed.
+    #[error("Error loading the source of a derived item")]
+    Source(#[source] AssetCacheError<DecoderError>),
+    /// The error comes from the [Transform].
+    #[error("Transform error computing a derived item")]
+    Transform(#[source] TransformError),
+}
+
+#[derive(Hash, Eq, PartialEq)]
+struct DerivedKey<K: ?Sized> {
+    source: Arc<K>,
+    /// The interned [Transform::id].
+    transform: u64,
+}
+
+type ErasedOutput = Arc<dyn Any + Send + Sync>;
+
+/// What [DerivedCache::get] returns.
+type DerivedResult<O, D, T> = Result<Arc<O>, DerivedCacheError<D, T>>;
+
+struct DerivedEntries<K: CacheKey + ?Sized> {
+    entries: Mutex<CostBasedLru<DerivedKey<K>, ErasedOutput>>,
+    /// Sources with outputs being computed, so that outputs computed from a source which was invalidated meanwhile
+    /// aren't cached.
+    ///
+    /// Entries only live while something is computing, so this doesn't grow with the number of sources.
+    in_flight: Mutex<HashMap<Arc<K>, InFlight, ahash::RandomState>>,
+    transform_ids: RwLock<HashMap<String, u64, ahash::RandomState>>,
+}
+
+struct InFlight {
+    /// How many outputs of the source are being computed.
+    computations: usize,
+    /// Bumped by every invalidation of the source.
+    generation: u64,
+}
+
+/// Registers a computation with [DerivedEntries::in_flight] until dropped.
+struct InFlightGuard<'a, K: CacheKey + ?Sized> {
+    entries: &'a DerivedEntries<K>,
+    source: Arc<K>,
+    generation: u64,
+}
+
+impl<K: CacheKey + ?Sized> DerivedEntries<K> {
+    fn transform_id(&self, id: &str) -> u64 {
+        if let Some(x) = self.transform_ids.read().unwrap().get(id) {
+            return *x;
+        }
+        let mut ids = self.transform_ids.write().unwrap();
+        let next = ids.len() as u64;
+        *ids.entry(id.to_string()).or_insert(next)
+    }
+
+    fn start(&self, source: Arc<K>) -> InFlightGuard<'_, K> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let entry = in_flight.entry(source.clone()).or_insert(InFlight {
+            computations: 0,
+            generation: 0,
+        });
+        entry.computations += 1;
+        InFlightGuard {
+            entries: self,
+            generation: entry.generation,
+            source,
+        }
+    }
+}
+
+impl<'a, K: CacheKey + ?Sized> InFlightGuard<'a, K> {
+    /// Whether the source has been invalidated since the computation started.
+    fn invalidated(&self) -> bool {
+        self.entries.in_flight.lock().unwrap()[&self.source].generation != self.generation
+    }
+}
+
+impl<'a, K: CacheKey + ?Sized> Drop for InFlightGuard<'a, K> {
+    fn drop(&mut self) {
+        let mut in_flight = self.entries.in_flight.lock().unwrap();
+        let entry = in_flight
+            .get_mut(&self.source)
+            .expect("Guards keep their entry");
+        entry.computations -= 1;
+        if entry.computations == 0 {
+            in_flight.remove(&self.source);
+        }
+    }
+}
+
+impl<K: CacheKey + ?Sized> InvalidationListener<K> for DerivedEntries<K> {
+    fn invalidate(&self, key: &K) {
+        if let Some(x) = self.in_flight.lock().unwrap().get_mut(key) {
+            x.generation += 1;
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|k, _| *k.source != *key);
+    }
+}
+
+/// Memoizes the outputs of [Transform]s of the items of an [AssetCache], under its own budget.
+///
+/// Outputs are evicted least recently used first once the budget is exceeded, and dropped when their source is
+/// removed from the cache or replaced, including by [AssetCache::reload].  Eviction of the source doesn't affect them.
+pub struct DerivedCache<VfsImpl, DecoderImpl, K = str>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    cache: Arc<AssetCache<VfsImpl, DecoderImpl, K>>,
+    entries: Arc<DerivedEntries<K>>,
+}
+
+impl<VfsImpl, DecoderImpl, K> DerivedCache<VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    pub fn new(
+        cache: Arc<AssetCache<VfsImpl, DecoderImpl, K>>,
+        max_cost: u64,
+    ) -> DerivedCache<VfsImpl, DecoderImpl, K> {
+        let entries = Arc::new(DerivedEntries {
+            entries: Mutex::new(CostBasedLru::new(max_cost)),
+            in_flight: Default::default(),
+            transform_ids: Default::default(),
+        });
+        let listener: Arc<dyn InvalidationListener<K>> = entries.clone();
+        cache.add_invalidation_listener(Arc::downgrade(&listener));
+        DerivedCache { cache, entries }
+    }
+
+    pub fn cache(&self) -> &Arc<AssetCache<VfsImpl, DecoderImpl, K>> {
+        &self.cache
+    }
+
+    /// Get the output of a transform of an item, loading the item and running the transform if it isn't cached.
+    ///
+    /// Outputs over the budget are returned without being cached.
+    pub fn get<T: Transform<DecoderImpl::Output>>(
+        &self,
+        key: &K,
+        transform: &T,
+    ) -> DerivedResult<T::Output, DecoderImpl::Error, T::Error> {
+        let derived_key = DerivedKey {
+            source: self.cache.normalize(key).to_shared(),
+            transform: self.entries.transform_id(transform.id()),
+        };
+        // An output of another type under the same id is a misconfiguration, and is replaced.
+        let found = self.entries.entries.lock().unwrap().get(&derived_key);
+        if let Some(x) = found.and_then(|x| (*x).clone().downcast::<T::Output>().ok()) {
+            return Ok(x);
+        }
+
+        let in_flight = self.entries.start(derived_key.source.clone());
+        let source = self
+            .cache
+            .find_or_decode(&derived_key.source, &mut 0)
+            .map_err(DerivedCacheError::Source)?;
+        let output = transform
+            .transform(source)
+            .map_err(DerivedCacheError::Transform)?;
+        let cost = transform
+            .estimate_cost(&output)
+            .map_err(DerivedCacheError::Transform)?;
+        let output = Arc::new(output);
+
+        let mut entries = self.entries.entries.lock().unwrap();
+        // Checking under the lock means an invalidation either happened before, and we see it, or waits for us and
+        // then drops what we insert.
+        if !in_flight.invalidated() {
+            let erased: ErasedOutput = output.clone();
+            entries.insert(Arc::new(derived_key), erased, cost);
+        }
+        Ok(output)
+    }
+
+    /// Drop every output derived from a key.
+    pub fn invalidate(&self, key: &K) {
+        self.entries.invalidate(&self.cache.normalize(key));
+    }
+
+    /// The total cost of the cached outputs.
+    pub fn current_cost(&self) -> u64 {
+        self.entries.entries.lock().unwrap().current_cost()
+    }
+}
+
+impl<VfsImpl, DecoderImpl, K> AssetCache<VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    DecoderImpl: DecodeWith<K::Params>,
+    K: CacheKey + ?Sized,
+{
+    /// Register a listener to be told when items are removed or replaced.
+    ///
+    /// The listener is called for [AssetCache::remove], [AssetCache::replace] and [AssetCache::reload], and by bulk
+    /// removals which include [RemovalScope::weak_refs], for each key they remove.  Listeners are held weakly, and are
+    /// forgotten once dropped.
+    pub fn add_invalidation_listener(&self, listener: Weak<dyn InvalidationListener<K>>) {
+        let mut listeners = self.invalidation_listeners.lock().unwrap();
+        listeners.retain(|l| l.strong_count() > 0);
+        listeners.push(listener);
+    }
+
+    pub(crate) fn notify_invalidated(&self, key: &K) {
+        let live = self
+            .invalidation_listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|l| l.upgrade())
+            .collect::<Vec<_>>();
+        for l in live {
+            l.invalidate(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::test_util::StringDecoder;
+
+    /// Counts the words of a string, recording how many times it ran.
+    #[derive(Default)]
+    struct WordCount(AtomicU64);
+
+    impl Transform<String> for WordCount {
+        type Output = usize;
+        type Error = std::io::Error;
+
+        fn id(&self) -> &str {
+            "word_count"
+        }
+
+        fn transform(&self, source: Arc<String>) -> Result<usize, std::io::Error> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(source.split_whitespace().count())
+        }
+
+        fn estimate_cost(&self, _output: &usize) -> Result<u64, std::io::Error> {
+            Ok(1)
+        }
+    }
+
+    struct Upper;
+
+    impl Transform<String> for Upper {
+        type Output = String;
+        type Error = std::io::Error;
+
+        fn id(&self) -> &str {
+            "upper"
+        }
+
+        fn transform(&self, source: Arc<String>) -> Result<String, std::io::Error> {
+            Ok(source.to_uppercase())
+        }
+
+        fn estimate_cost(&self, output: &String) -> Result<u64, std::io::Error> {
+            Ok(output.len() as u64)
+        }
+    }
+
+    /// Runs a callback in the middle of transforming, then returns the length of the source.
+    struct During<'a>(&'a (dyn Fn() + Send + Sync));
+
+    impl<'a> Transform<String> for During<'a> {
+        type Output = usize;
+        type Error = std::io::Error;
+
+        fn id(&self) -> &str {
+            "during"
+        }
+
+        fn transform(&self, source: Arc<String>) -> Result<usize, std::io::Error> {
+            (self.0)();
+            Ok(source.len())
+        }
+
+        fn estimate_cost(&self, _output: &usize) -> Result<u64, std::io::Error> {
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_derived_cache() {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(100)
+            .max_decoded_cost(100)
+            .max_single_object_decoded_cost(100)
+            .build()
+            .unwrap();
+        let vfs = Arc::new(MemoryVfs::new());
+        vfs.insert("a", "one two".as_bytes());
+        vfs.insert("b", "three".as_bytes());
+        let derived = DerivedCache::new(
+            Arc::new(
+                AssetCache::new(vfs.clone(), StringDecoder, cfg)
+                    .with_key_normalizer(DefaultKeyNormalizer::default()),
+            ),
+            16,
+        );
+        let count = WordCount::default();
+
+        assert_eq!(*derived.get("/a", &count).unwrap(), 2);
+        assert_eq!(*derived.get("a", &count).unwrap(), 2);
+        assert_eq!(&*derived.get("a", &Upper).unwrap(), "ONE TWO");
+        assert_eq!(count.0.load(Ordering::Relaxed), 1);
+        assert_eq!(derived.current_cost(), 8);
+
+        // Evicting the source doesn't matter, but reloading it does.
+        derived.cache().clear(RemovalScope::CACHED);
+        assert_eq!(*derived.get("a", &count).unwrap(), 2);
+        assert_eq!(count.0.load(Ordering::Relaxed), 1);
+        vfs.insert("a", "one two three".as_bytes());
+        derived.cache().reload("a").unwrap();
+        assert_eq!(derived.current_cost(), 0);
+        assert_eq!(*derived.get("a", &count).unwrap(), 3);
+
+        // Going over budget evicts the least recently used outputs.
+        derived.get("b", &count).unwrap();
+        derived.get("b", &Upper).unwrap();
+        assert_eq!(derived.current_cost(), 7);
+        derived.get("a", &Upper).unwrap();
+        assert_eq!(derived.current_cost(), 13);
+        derived.get("a", &count).unwrap();
+        assert_eq!(count.0.load(Ordering::Relaxed), 4);
+
+        // Invalidating one source while computing from another doesn't stop the other's output being cached.
+        let invalidate_b = || derived.invalidate("b");
+        let cost = derived.current_cost();
+        derived.get("a", &During(&invalidate_b)).unwrap();
+        assert_eq!(derived.current_cost(), cost + 1);
+        derived.get("b", &During(&invalidate_b)).unwrap();
+        assert_eq!(derived.current_cost(), cost + 1);
+
+        derived.cache().remove("a");
+        assert_eq!(derived.current_cost(), 0);
+        assert!(matches!(
+            derived.get("missing", &count),
+            Err(DerivedCacheError::Source(AssetCacheError::Vfs(_)))
+        ));
+    }
+}
@@ -0,0 +1,380 @@
+//! An optional persistent tier under the decoded tier of the [AssetCache](crate::AssetCache), for outputs which are
+//! expensive to produce.
+//!
+//! Each entry is a file in a cache directory, named by a hash of the asset key, the [VfsMetadata::version] and
+//! [VfsReader::content_hash] of the asset where available, and the [Decoder::version].  Changing any of them means a
+//! different file, so stale outputs are never served; the old ones just age out.  The key and stamp are also stored in
+//! the file and checked on load, which deals with hash collisions.  Assets with neither a version nor a content hash
+//! are never persisted.
+//!
+//! Files are written to a temporary name, synced, and renamed into place, and the directory is synced after the rename,
+//! so a crash never leaves a partial entry behind.  The
+//! directory has its own cost budget in bytes, with LRU eviction.  Recency is tracked in memory and seeded from
+//! modification times when the tier is opened.
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::*;
+
+const MAGIC: &[u8; 8] = b"ALRUDSK1";
+const EXTENSION: &str = "bin";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// A decoded output which can be written to and read back from the disk tier.
+pub trait PersistentOutput: Sized {
+    fn serialize(&self, writer: &mut dyn Write) -> Result<()>;
+
+    fn deserialize(reader: &mut dyn Read) -> Result<Self>;
+}
+
+impl PersistentOutput for Vec<u8> {
+    fn serialize(&self, writer: &mut dyn Write) -> Result<()> {
+        writer.write_all(self)
+    }
+
+    fn deserialize(reader: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        reader.read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+impl PersistentOutput for String {
+    fn serialize(&self, writer: &mut dyn Write) -> Result<()> {
+        writer.write_all(self.as_bytes())
+    }
+
+    fn deserialize(reader: &mut dyn Read) -> Result<String> {
+        let mut out = String::new();
+        reader.read_to_string(&mut out)?;
+        Ok(out)
+    }
+}
+
+/// Configuration for the disk tier of an [AssetCache](crate::AssetCache).
+#[derive(Debug, derive_builder::Builder)]
+pub struct DiskTierConfig {
+    /// The directory to keep entries in.  Created if it doesn't exist.
+    ///
+    /// This should be dedicated to one cache: anything else in it which looks like an entry may be deleted.
+    #[builder(setter(into))]
+    pub directory: PathBuf,
+    /// Maximum total size of the entries, in bytes.
+    pub max_cost: u64,
+}
+
+/// What an entry was produced from.  An entry is only used if all of this matches.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct DiskStamp {
+    pub(crate) vfs_version: Option<u64>,
+    pub(crate) content_hash: Option<u64>,
+    pub(crate) decoder_version: u64,
+}
+
+impl DiskStamp {
+    /// Whether the stamp identifies the content at all.  Without a version or a hash, it can't.
+    pub(crate) fn identifies_content(&self) -> bool {
+        self.vfs_version.is_some() || self.content_hash.is_some()
+    }
+
+    fn encode(&self) -> [u8; 26] {
+        let mut out = [0u8; 26];
+        for (i, x) in [self.vfs_version, self.content_hash].iter().enumerate() {
+            if let Some(x) = x {
+                out[i * 9] = 1;
+                out[i * 9 + 1..i * 9 + 9].copy_from_slice(&x.to_le_bytes());
+            }
+        }
+        out[18..].copy_from_slice(&self.decoder_version.to_le_bytes());
+        out
+    }
+}
+
+pub(crate) struct DiskTier<T> {
+    directory: PathBuf,
+    max_cost: u64,
+    /// File names to their sizes.
+    index: Mutex<CostBasedLru<str, u64>>,
+    /// For unique temporary names.
+    temp_counter: AtomicU64,
+    serialize: fn(&T, &mut dyn Write) -> Result<()>,
+    deserialize: fn(&mut dyn Read) -> Result<T>,
+}
+
+fn write_header(writer: &mut dyn Write, key: &str, stamp: &DiskStamp) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(key.len() as u64).to_le_bytes())?;
+    writer.write_all(key.as_bytes())?;
+    writer.write_all(&stamp.encode())
+}
+
+fn read_u64(reader: &mut dyn Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Check that the header matches what we expect.
+fn check_header(reader: &mut dyn Read, key: &str, stamp: &DiskStamp) -> Result<bool> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u64(reader)? != key.len() as u64 {
+        return Ok(false);
+    }
+
+    let mut stored_key = vec![0u8; key.len()];
+    reader.read_exact(&mut stored_key)?;
+    let mut stored_stamp = [0u8; 26];
+    reader.read_exact(&mut stored_stamp)?;
+    Ok(stored_key == key.as_bytes() && stored_stamp == stamp.encode())
+}
+
+impl<T: PersistentOutput> DiskTier<T> {
+    pub(crate) fn open(config: DiskTierConfig) -> Result<DiskTier<T>> {
+        std::fs::create_dir_all(&config.directory)?;
+
+        // Rebuild the index, oldest first so that the most recent files end up most recently used.
+        let mut found = vec![];
+        for entry in std::fs::read_dir(&config.directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            let ext = path.extension().and_then(|x| x.to_str());
+            if ext == Some(TEMP_EXTENSION) {
+                // Left over from a crash.
+                let _ = std::fs::remove_file(&path);
+            } else if ext == Some(EXTENSION) {
+                let meta = entry.metadata()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                found.push((meta.modified().ok(), name, meta.len()));
+            }
+        }
+        found.sort();
+
+        let tier = DiskTier {
+            directory: config.directory,
+            max_cost: config.max_cost,
+            index: Mutex::new(CostBasedLru::new(u64::MAX)),
+            temp_counter: AtomicU64::new(0),
+            serialize: T::serialize,
+            deserialize: T::deserialize,
+        };
+        {
+            let mut index = tier.index.lock().unwrap();
+            for (_, name, size) in found {
+                index.insert(name.into(), size, size);
+            }
+            tier.evict(&mut index);
+        }
+        Ok(tier)
+    }
+}
+
+impl<T> DiskTier<T> {
+    fn file_name(key: &str, stamp: &DiskStamp) -> String {
+        let mut hasher = Fnv::new();
+        hasher.write(key.as_bytes());
+        hasher.write(&[0]);
+        hasher.write(&stamp.encode());
+        format!("{:016x}.{}", hasher.0, EXTENSION)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+
+    fn evict(&self, index: &mut CostBasedLru<str, u64>) {
+        while index.current_cost() > self.max_cost {
+            let (name, _) = index.pop_lru().expect("Cost is nonzero");
+            let _ = std::fs::remove_file(self.path(&name));
+        }
+    }
+
+    fn forget(&self, name: &str) {
+        self.index.lock().unwrap().remove(name);
+        let _ = std::fs::remove_file(self.path(name));
+    }
+
+    /// Load an entry, returning `None` on a miss.
+    ///
+    /// Entries which fail to load are deleted.
+    pub(crate) fn load(&self, key: &str, stamp: &DiskStamp) -> Option<T> {
+        let name = Self::file_name(key, stamp);
+        self.index.lock().unwrap().get(&*name)?;
+
+        let attempt = || -> Result<Option<T>> {
+            let mut reader = BufReader::new(File::open(self.path(&name))?);
+            if !check_header(&mut reader, key, stamp)? {
+                return Ok(None);
+            }
+            (self.deserialize)(&mut reader).map(Some)
+        };
+
+        match attempt() {
+            Ok(x) => x,
+            Err(_) => {
+                self.forget(&name);
+                None
+            }
+        }
+    }
+
+    /// Store an entry, replacing any previous entry for the same key and stamp.
+    pub(crate) fn store(&self, key: &str, stamp: &DiskStamp, item: &T) -> Result<()> {
+        let name = Self::file_name(key, stamp);
+        let temp = self.path(&format!(
+            "{}-{}-{}.{}",
+            name,
+            std::process::id(),
+            self.temp_counter.fetch_add(1, Ordering::Relaxed),
+            TEMP_EXTENSION
+        ));
+
+        let write = || -> Result<u64> {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            write_header(&mut writer, key, stamp)?;
+            (self.serialize)(item, &mut writer)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            let size = file.metadata()?.len();
+            std::fs::rename(&temp, self.path(&name))?;
+            sync_directory(&self.directory)?;
+            Ok(size)
+        };
+
+        let size = match write() {
+            Ok(s) => s,
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp);
+                return Err(e);
+            }
+        };
+
+        let mut index = self.index.lock().unwrap();
+        index.insert(name.into(), size, size);
+        self.evict(&mut index);
+        Ok(())
+    }
+}
+
+/// Make a rename in `dir` durable.
+#[cfg(unix)]
+fn sync_directory(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms, and renames there are as durable as they get.
+#[cfg(not(unix))]
+fn sync_directory(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use super::*;
+
+    struct CountingDecoder {
+        decodes: Arc<AtomicUsize>,
+        version: u64,
+    }
+
+    impl Decoder for CountingDecoder {
+        type Error = std::io::Error;
+        type Output = String;
+
+        fn decode<R: Read>(&self, mut reader: R) -> Result<String> {
+            self.decodes.fetch_add(1, Ordering::Relaxed);
+            let mut out = String::new();
+            reader.read_to_string(&mut out)?;
+            Ok(out)
+        }
+
+        fn version(&self) -> u64 {
+            self.version
+        }
+
+        fn estimate_cost(&self, item: &String) -> Result<u64> {
+            Ok(item.len() as u64)
+        }
+    }
+
+    fn build_cache(
+        vfs: &Arc<MemoryVfs>,
+        dir: &std::path::Path,
+        version: u64,
+        decodes: &Arc<AtomicUsize>,
+    ) -> AssetCache<Arc<MemoryVfs>, CountingDecoder> {
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(0)
+            .max_single_object_bytes_cost(0)
+            .max_decoded_cost(100)
+            .max_single_object_decoded_cost(100)
+            .build()
+            .unwrap();
+        let decoder = CountingDecoder {
+            decodes: decodes.clone(),
+            version,
+        };
+        AssetCache::new(vfs.clone(), decoder, cfg)
+            .with_disk_tier(
+                DiskTierConfigBuilder::default()
+                    .directory(dir)
+                    .max_cost(200)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_disk_tier() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = tmp_dir.path();
+        let vfs = Arc::new(MemoryVfs::new());
+        vfs.insert("a", &b"aaa"[..]);
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let cache = build_cache(&vfs, dir, 0, &counter);
+        assert_eq!(&*cache.get("a").unwrap(), "aaa");
+        assert_eq!(counter.swap(0, Ordering::Relaxed), 1);
+
+        // A fresh cache over the same directory doesn't need to decode.
+        let cache = build_cache(&vfs, dir, 0, &counter);
+        assert_eq!(&*cache.get("a").unwrap(), "aaa");
+        assert_eq!(counter.swap(0, Ordering::Relaxed), 0);
+
+        // Changing the content changes the version, so the persisted output is stale.
+        vfs.replace("a", &b"new"[..]);
+        let cache = build_cache(&vfs, dir, 0, &counter);
+        assert_eq!(&*cache.get("a").unwrap(), "new");
+        assert_eq!(counter.swap(0, Ordering::Relaxed), 1);
+
+        // As does bumping the decoder version.
+        let cache = build_cache(&vfs, dir, 1, &counter);
+        assert_eq!(&*cache.get("a").unwrap(), "new");
+        assert_eq!(counter.swap(0, Ordering::Relaxed), 1);
+        let cache = build_cache(&vfs, dir, 1, &counter);
+        cache.get("a").unwrap();
+        assert_eq!(counter.swap(0, Ordering::Relaxed), 0);
+
+        // Leftover temporaries are cleaned up, and the directory stays under budget.
+        std::fs::write(dir.join("junk.bin-1-1.tmp"), "junk").unwrap();
+        let cache = build_cache(&vfs, dir, 1, &counter);
+        assert!(!dir.join("junk.bin-1-1.tmp").exists());
+        for i in 0..20 {
+            let key = format!("k{}", i);
+            vfs.insert(&key, key.as_bytes());
+            cache.get(&key).unwrap();
+        }
+        let total: u64 = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(total <= 200);
+    }
+}
@@ -0,0 +1,257 @@
+//! A [Vfs] over files baked into the binary at compile time.
+//!
+//! To embed a directory, call [generate_embedded_table] from a build script:
+//!
+//! ```no_run
+//! // In build.rs:
+//! let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("assets.rs");
+//! asset_lru::generate_embedded_table("assets".as_ref(), &out).unwrap();
+//! println!("cargo:rerun-if-changed=assets");
+//! ```
+//!
+//! Which writes a `&'static [(&'static str, &'static [u8])]` expression using `include_bytes!`, for use with
+//! [EmbeddedVfs::new]:
+//!
+//! ```ignore
+//! static ASSETS: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+//! let vfs = asset_lru::EmbeddedVfs::new(ASSETS);
+//! ```
+//!
+//! To let files on disk override the embedded ones, put the two in a [LayeredVfs].
+use std::io::{Cursor, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use crate::*;
+
+/// A [Vfs] serving a static table of `(key, bytes)` pairs without copying.
+///
+/// Keys in the table should be in the form produced by [DefaultKeyNormalizer].  See the module documentation for how
+/// to generate the table from a directory.
+///
+/// The [VfsMetadata::version] of each entry is a hash of its content, so that it changes when a rebuild changes the
+/// content.  Each version is computed the first time it is asked for.
+#[derive(Debug)]
+pub struct EmbeddedVfs {
+    entries: std::collections::HashMap<&'static str, EmbeddedEntry, ahash::RandomState>,
+}
+
+#[derive(Debug)]
+struct EmbeddedEntry {
+    data: &'static [u8],
+    version: std::sync::OnceLock<u64>,
+}
+
+impl EmbeddedVfs {
+    pub fn new(table: &'static [(&'static str, &'static [u8])]) -> EmbeddedVfs {
+        EmbeddedVfs {
+            entries: table
+                .iter()
+                .map(|(k, data)| {
+                    let entry = EmbeddedEntry {
+                        data,
+                        version: Default::default(),
+                    };
+                    (*k, entry)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Vfs for EmbeddedVfs {
+    type Reader = Cursor<&'static [u8]>;
+
+    fn open(&self, key: &str) -> Result<Self::Reader> {
+        self.entries
+            .get(&*normalize_default(key))
+            .map(|x| Cursor::new(x.data))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Entry not found"))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = normalize_default(prefix);
+        Ok(self
+            .entries
+            .keys()
+            .filter(|k| is_under_prefix(k, &prefix))
+            .map(|k| k.to_string())
+            .collect())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        let key = normalize_default(key);
+        Ok(self.entries.contains_key(&*key)
+            || self.entries.keys().any(|k| is_under_prefix(k, &key)))
+    }
+
+    fn metadata(&self, key: &str) -> Result<VfsMetadata> {
+        let key = normalize_default(key);
+        if let Some(e) = self.entries.get(&*key) {
+            return Ok(VfsMetadata {
+                size: e.data.len() as u64,
+                version: Some(*e.version.get_or_init(|| content_version(e.data))),
+                is_dir: false,
+            });
+        }
+
+        if self.entries.keys().any(|k| is_under_prefix(k, &key)) {
+            return Ok(VfsMetadata {
+                size: 0,
+                version: None,
+                is_dir: true,
+            });
+        }
+
+        Err(Error::new(ErrorKind::NotFound, "Entry not found"))
+    }
+}
+
+/// For use from build scripts: write a Rust expression embedding every file under `dir` to `out`.
+///
+/// The expression is a `&'static [(&'static str, &'static [u8])]` suitable for [EmbeddedVfs::new], with keys relative
+/// to `dir` and separated by `/`.  Files are embedded with `include_bytes!` using absolute paths, so the output can be
+/// `include!`d from anywhere.
+///
+/// Symlinks are skipped rather than followed, so that a link can't pull files from outside `dir` into the binary or
+/// send the walk round in a loop.
+pub fn generate_embedded_table(dir: &Path, out: &Path) -> Result<()> {
+    let dir = dir.canonicalize()?;
+    let mut files = vec![];
+    collect_files(&dir, "", &mut files)?;
+    // Sorting keeps the output stable, so that cargo doesn't rebuild for no reason.
+    files.sort();
+
+    let mut dest = std::io::BufWriter::new(std::fs::File::create(out)?);
+    writeln!(dest, "&[")?;
+    for (key, path) in files {
+        writeln!(dest, "    ({:?}, include_bytes!({:?})),", key, path)?;
+    }
+    writeln!(dest, "]")?;
+    dest.flush()
+}
+
+fn collect_files(dir: &Path, prefix: &str, out: &mut Vec<(String, String)>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| Error::other("File name is not valid UTF-8"))?;
+        let key = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+
+        let path = entry.path();
+        // `DirEntry::file_type` doesn't follow symlinks.
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&path, &key, out)?;
+        } else if file_type.is_file() {
+            let path = path
+                .to_str()
+                .ok_or_else(|| Error::other("Path is not valid UTF-8"))?
+                .to_string();
+            out.push((key, path));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    static TABLE: &[(&str, &[u8])] = &[("a", b"aaa"), ("dir/b", b"bbb")];
+
+    #[test]
+    fn test_embedded_vfs() {
+        let vfs = EmbeddedVfs::new(TABLE);
+        let mut out = String::new();
+        vfs.open("/dir/./b")
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "bbb");
+        assert_eq!(vfs.open("c").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(vfs.list("dir").unwrap(), vec!["dir/b"]);
+        assert!(vfs.exists("dir").unwrap());
+        assert_eq!(vfs.metadata("a").unwrap().size, 3);
+
+        // Versions follow the content, not the process.
+        let rebuilt = EmbeddedVfs::new(&[("a", b"aaa"), ("dir/b", b"new")]);
+        let version = |vfs: &EmbeddedVfs, k| vfs.metadata(k).unwrap().version.unwrap();
+        assert_eq!(version(&vfs, "a"), version(&rebuilt, "a"));
+        assert_ne!(version(&vfs, "dir/b"), version(&rebuilt, "dir/b"));
+
+        // Readers, and the cache's bytes, point straight at the table.
+        assert_eq!(
+            vfs.open("a").unwrap().mapped_bytes().unwrap().as_ptr(),
+            TABLE[0].1.as_ptr()
+        );
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(100)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .build()
+            .unwrap();
+        let cache = AssetCache::new(vfs, SharedDecoder, cfg);
+        assert_eq!(cache.get("dir/b").unwrap().as_ptr(), TABLE[1].1.as_ptr());
+        assert_eq!(cache.get("dir/b").unwrap().as_ptr(), TABLE[1].1.as_ptr());
+    }
+
+    struct SharedDecoder;
+
+    impl Decoder for SharedDecoder {
+        type Error = Error;
+        type Output = SharedBytes;
+
+        fn decode<R: Read>(&self, mut reader: R) -> Result<SharedBytes> {
+            let mut out = vec![];
+            reader.read_to_end(&mut out)?;
+            Ok(out.into())
+        }
+
+        fn estimate_cost(&self, item: &SharedBytes) -> Result<u64> {
+            Ok(item.len() as u64)
+        }
+
+        fn decode_shared(&self, bytes: SharedBytes) -> Result<SharedBytes> {
+            Ok(bytes)
+        }
+    }
+
+    #[test]
+    fn test_generate_embedded_table() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let assets = tmp_dir.path().join("assets");
+        std::fs::create_dir_all(assets.join("sub")).unwrap();
+        std::fs::write(assets.join("a"), "aaa").unwrap();
+        std::fs::write(assets.join("sub").join("b"), "bbb").unwrap();
+        // Links are left out, whether they point at files or back up the tree.
+        #[cfg(unix)]
+        {
+            std::fs::write(tmp_dir.path().join("outside"), "secret").unwrap();
+            std::os::unix::fs::symlink(tmp_dir.path().join("outside"), assets.join("link"))
+                .unwrap();
+            std::os::unix::fs::symlink(&assets, assets.join("sub").join("loop")).unwrap();
+        }
+
+        let out = tmp_dir.path().join("table.rs");
+        generate_embedded_table(&assets, &out).unwrap();
+        let generated = std::fs::read_to_string(&out).unwrap();
+
+        let canonical = assets.canonicalize().unwrap();
+        let expected = format!(
+            "&[\n    (\"a\", include_bytes!({:?})),\n    (\"sub/b\", include_bytes!({:?})),\n]\n",
+            canonical.join("a").to_str().unwrap(),
+            canonical.join("sub").join("b").to_str().unwrap(),
+        );
+        assert_eq!(generated, expected);
+    }
+}
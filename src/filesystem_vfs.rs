@@ -4,48 +4,241 @@ use std::path::{Path, PathBuf};
 
 use crate::*;
 
+/// How a [FilesystemVfs] keeps paths inside its root directory.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Confinement {
+    /// Normalize the path logically, then check that the result is under the root.
+    ///
+    /// This catches bugs, but symlinks inside the root are followed wherever they point.
+    #[default]
+    Logical,
+    /// Resolve the path one component at a time, refusing to follow symlinks out of the root.
+    ///
+    /// Symlinks which stay inside the root still work.  Use this when the root contains content you don't control,
+    /// for example user-downloaded mods.  Escapes fail with [ErrorKind::PermissionDenied], which [is_root_escape] can
+    /// tell apart from other errors.
+    ///
+    /// On Unix this is done with `openat` and `O_NOFOLLOW` and is safe against concurrent modification of the tree.
+    /// Elsewhere it falls back to canonicalizing the path, which isn't.
+    Strict,
+}
+
 /// A VFS which is backed by a given root directory.
 ///
 /// This handles the rather tricky path cases around Windows and Linux differences, and makes it so that you can and
 /// should use keys like `/b/c` (behavior with `\` is undefined).  Additionally, it makes a best effort to disallow a
-/// user to use relative paths to escape the root directory, primarily as a measure to detect bugs.
+/// user to use relative paths to escape the root directory, primarily as a measure to detect bugs.  For actual
+/// protection against untrusted content, see [Confinement::Strict].
+///
+/// With the `mmap` feature, files at or above a size threshold can be memory-mapped rather than read.  See
+/// [FilesystemVfs::with_mmap_threshold].
 #[derive(Debug)]
 pub struct FilesystemVfs {
     root_path: PathBuf,
+    confinement: Confinement,
+    #[cfg(feature = "mmap")]
+    mmap_threshold: Option<u64>,
+}
+
+/// The reader returned by [FilesystemVfs]: either the open file, or a memory mapping of it.
+#[derive(Debug)]
+pub struct FilesystemReader {
+    inner: ReaderInner,
+}
+
+#[derive(Debug)]
+enum ReaderInner {
+    File(File),
+    #[cfg(feature = "mmap")]
+    Mapped(Cursor<MappedFile>),
+}
+
+#[cfg(feature = "mmap")]
+#[derive(Clone, Debug)]
+struct MappedFile(std::sync::Arc<memmap2::Mmap>);
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
 }
 
 fn conv_path(path: impl AsRef<Path>) -> Result<relative_path::RelativePathBuf> {
-    relative_path::RelativePathBuf::from_path(path)
-        .map_err(|_| Error::new(ErrorKind::Other, "Invalid path"))
+    relative_path::RelativePathBuf::from_path(path).map_err(|_| Error::other("Invalid path"))
 }
 
 impl FilesystemVfs {
     pub fn new(root_path: &Path) -> std::io::Result<FilesystemVfs> {
+        FilesystemVfs::with_confinement(root_path, Default::default())
+    }
+
+    pub fn with_confinement(
+        root_path: &Path,
+        confinement: Confinement,
+    ) -> std::io::Result<FilesystemVfs> {
         Ok(FilesystemVfs {
             root_path: root_path.to_path_buf(),
+            confinement,
+            #[cfg(feature = "mmap")]
+            mmap_threshold: None,
         })
     }
 
-    /// Run the file opening logic on the VFS, so that this can be reused for normal file access at the same time.
-    pub fn open_file(&self, path: &Path) -> std::io::Result<File> {
+    /// Memory-map files of at least `threshold` bytes instead of reading them.
+    ///
+    /// Mapped files are kept in the bytes tier of the [AssetCache] without being copied, and are handed to
+    /// [Decoder::decode_bytes] directly.  See [AssetCacheConfig::mapped_bytes_cost_percent] for how they are charged.
+    ///
+    /// # Safety
+    ///
+    /// Files under the root must not be modified or truncated while any reader or cached buffer maps them, by this
+    /// process or any other.  Depending on the platform, modification either fails or changes memory which safe code
+    /// assumes is immutable, and truncation can crash the process.  Only use this for directories which are read-only
+    /// while the program runs, such as installed game data.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn with_mmap_threshold(mut self, threshold: u64) -> FilesystemVfs {
+        self.mmap_threshold = Some(threshold);
+        self
+    }
+
+    /// Resolve a path relative to the root of the VFS, failing if it would escape the root.
+    fn resolve(&self, path: &Path) -> std::io::Result<PathBuf> {
         // On Windows, canonicalize is currently very broken when relative path segments appear in the middle of a
         // path, and stdlib doesn't help us out. Go via `RelativePathBuf` to clean it up.
         let absolute = conv_path(path)?.to_logical_path(&self.root_path);
         if !absolute.starts_with(&self.root_path) {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "path is outside the vfs root directory",
-            ));
+            return Err(Error::other("path is outside the vfs root directory"));
+        }
+        Ok(absolute)
+    }
+
+    /// Return the canonical form of a key: the normalized path, relative to the root, that this VFS resolves it to.
+    ///
+    /// This is the same as what [DefaultKeyNormalizer] produces for keys which don't escape the root.  Symlinks are
+    /// not resolved.
+    pub fn canonical_key(&self, key: &str) -> std::io::Result<String> {
+        self.resolve(Path::new(key))?;
+        Ok(conv_path(key)?.normalize().into_string())
+    }
+
+    /// Run the file opening logic on the VFS, so that this can be reused for normal file access at the same time.
+    pub fn open_file(&self, path: &Path) -> std::io::Result<File> {
+        match self.confinement {
+            Confinement::Logical => File::open(self.resolve(path)?),
+            Confinement::Strict => open_confined(&self.root_path, path),
+        }
+    }
+
+    fn stat(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        match self.confinement {
+            Confinement::Logical => std::fs::metadata(self.resolve(path)?),
+            Confinement::Strict => metadata_confined(&self.root_path, path),
+        }
+    }
+
+    /// The entries of the directory at `path`, without following links for their kinds.
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<(std::ffi::OsString, EntryKind)>> {
+        match self.confinement {
+            Confinement::Logical => std::fs::read_dir(self.resolve(path)?)?
+                .map(|e| {
+                    let e = e?;
+                    Ok((e.file_name(), e.file_type()?.into()))
+                })
+                .collect(),
+            Confinement::Strict => read_dir_confined(&self.root_path, path),
         }
-        File::open(absolute)
+    }
+
+    /// Recursively collect the keys of all files under the key `prefix`.
+    fn list_into(
+        &self,
+        prefix: &relative_path::RelativePath,
+        out: &mut Vec<String>,
+    ) -> std::io::Result<()> {
+        for (name, kind) in self.read_dir(prefix.as_str().as_ref())? {
+            let name = name
+                .to_str()
+                .ok_or_else(|| Error::other("File name is not valid UTF-8"))?;
+            let key = prefix.join(name);
+            // Only real directories are descended into.
+            if kind == EntryKind::Dir {
+                self.list_into(&key, out)?;
+                continue;
+            }
+            // Links to files are listed like the files they point at.  Links to directories, which can form cycles,
+            // and links which leave the root, dangle or loop are skipped rather than failing the whole listing.
+            if kind == EntryKind::Symlink {
+                match self.stat(key.as_str().as_ref()) {
+                    Ok(m) if !m.is_dir() => {}
+                    _ => continue,
+                }
+            }
+            out.push(key.into_string());
+        }
+
+        Ok(())
+    }
+}
+
+fn metadata_to_vfs(meta: std::fs::Metadata) -> VfsMetadata {
+    // Truncating to 64 bits is fine: this only has to change when the file does.
+    let version = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64);
+    VfsMetadata {
+        size: if meta.is_dir() { 0 } else { meta.len() },
+        version,
+        is_dir: meta.is_dir(),
     }
 }
 
 impl Vfs for FilesystemVfs {
-    type Reader = File;
+    type Reader = FilesystemReader;
+
+    fn open(&self, key: &str) -> std::io::Result<FilesystemReader> {
+        let file = self.open_file(Path::new(key))?;
+
+        #[cfg(feature = "mmap")]
+        if let Some(threshold) = self.mmap_threshold {
+            let len = file.get_size()?;
+            // Empty files can't be mapped on all platforms.
+            if len >= threshold && len > 0 {
+                // Safety: the caller of with_mmap_threshold promised that files aren't modified while mapped.
+                let map = unsafe { memmap2::Mmap::map(&file)? };
+                return Ok(FilesystemReader {
+                    inner: ReaderInner::Mapped(Cursor::new(MappedFile(std::sync::Arc::new(map)))),
+                });
+            }
+        }
+
+        Ok(FilesystemReader {
+            inner: ReaderInner::File(file),
+        })
+    }
 
-    fn open(&self, key: &str) -> std::io::Result<File> {
-        self.open_file(Path::new(key))
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        if !self.stat(Path::new(prefix))?.is_dir() {
+            return Err(Error::other("Listing prefix is not a directory"));
+        }
+        let prefix = conv_path(prefix)?.normalize();
+        let mut out = vec![];
+        self.list_into(&prefix, &mut out)?;
+        Ok(out)
+    }
+
+    fn exists(&self, key: &str) -> std::io::Result<bool> {
+        match self.stat(Path::new(key)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn metadata(&self, key: &str) -> std::io::Result<VfsMetadata> {
+        Ok(metadata_to_vfs(self.stat(Path::new(key))?))
     }
 }
 
@@ -56,6 +249,44 @@ impl VfsReader for File {
     }
 }
 
+impl Read for FilesystemReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match &mut self.inner {
+            ReaderInner::File(x) => x.read(buf),
+            #[cfg(feature = "mmap")]
+            ReaderInner::Mapped(x) => x.read(buf),
+        }
+    }
+}
+
+impl Seek for FilesystemReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match &mut self.inner {
+            ReaderInner::File(x) => x.seek(pos),
+            #[cfg(feature = "mmap")]
+            ReaderInner::Mapped(x) => x.seek(pos),
+        }
+    }
+}
+
+impl VfsReader for FilesystemReader {
+    fn get_size(&self) -> Result<u64> {
+        match &self.inner {
+            ReaderInner::File(x) => x.get_size(),
+            #[cfg(feature = "mmap")]
+            ReaderInner::Mapped(x) => Ok(x.get_ref().0.len() as u64),
+        }
+    }
+
+    fn mapped_bytes(&self) -> Option<SharedBytes> {
+        match &self.inner {
+            ReaderInner::File(_) => None,
+            #[cfg(feature = "mmap")]
+            ReaderInner::Mapped(x) => Some(SharedBytes::new(x.get_ref().clone())),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,6 +315,13 @@ mod tests {
             max_bytes_cost: 1000,
             max_decoded_cost: 1000,
             max_single_object_decoded_cost: 1000,
+            bytes_compression: Default::default(),
+            mapped_bytes_cost_percent: 100,
+            unified_budget: None,
+            low_watermark_percent: 100,
+            count_unmanaged_cost: false,
+            max_pinned_cost: None,
+            validate_content_hash: false,
         };
 
         let tmp_dir = tempfile::tempdir().unwrap();
@@ -97,7 +335,7 @@ mod tests {
             AssetCache::<FilesystemVfs, StringDecoder>::new(vfs, StringDecoder, cache_config);
 
         // Now let's write some files.
-        std::fs::write(&vfs_path.join("a"), "aaaa").unwrap();
+        std::fs::write(vfs_path.join("a"), "aaaa").unwrap();
         std::fs::write(vfs_path.join("b"), "bbbb").unwrap();
         std::fs::write(vfs_path.join("c"), "cccc").unwrap();
         // Now, we want to write something outside the vfs.
@@ -120,4 +358,128 @@ mod tests {
             panic!("Should error when getting files outside the vfs directory");
         }
     }
+
+    #[test]
+    fn test_filesystem_vfs_listing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let vfs_path = tmp_dir.path();
+        for k in ["a", "b", "c"] {
+            std::fs::write(vfs_path.join(k), k).unwrap();
+        }
+        std::fs::create_dir(vfs_path.join("sub")).unwrap();
+        std::fs::write(vfs_path.join("sub").join("e"), "eeeee").unwrap();
+        let vfs = FilesystemVfs::new(vfs_path).unwrap();
+        let mut listed = vfs.list("").unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["a", "b", "c", "sub/e"]);
+        assert_eq!(vfs.list("sub").unwrap(), vec!["sub/e"]);
+        assert!(vfs.exists("sub/e").unwrap());
+        assert!(!vfs.exists("nope").unwrap());
+        let meta = vfs.metadata("sub/e").unwrap();
+        assert_eq!(meta.size, 5);
+        assert!(!meta.is_dir);
+        assert!(vfs.metadata("sub").unwrap().is_dir);
+        assert!(vfs.list("..").is_err());
+        #[cfg(unix)]
+        {
+            // Link cycles and dangling links don't break listing.
+            std::os::unix::fs::symlink("loop_b", vfs_path.join("sub").join("loop_a")).unwrap();
+            std::os::unix::fs::symlink("loop_a", vfs_path.join("sub").join("loop_b")).unwrap();
+            std::os::unix::fs::symlink("..", vfs_path.join("sub").join("up")).unwrap();
+            std::os::unix::fs::symlink("missing", vfs_path.join("sub").join("dangling")).unwrap();
+            assert_eq!(vfs.list("sub").unwrap(), vec!["sub/e"]);
+        }
+        assert_eq!(vfs.canonical_key("sub/../sub/./e").unwrap(), "sub/e");
+        assert!(vfs.canonical_key("../d").is_err());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("small"), "small").unwrap();
+        std::fs::write(tmp_dir.path().join("big"), "0123456789").unwrap();
+        std::fs::write(tmp_dir.path().join("empty"), "").unwrap();
+        // Safety: nothing modifies the temporary directory while the test runs.
+        let vfs = unsafe {
+            FilesystemVfs::new(tmp_dir.path())
+                .unwrap()
+                .with_mmap_threshold(10)
+        };
+
+        assert!(vfs.open("small").unwrap().mapped_bytes().is_none());
+        assert!(vfs.open("empty").unwrap().mapped_bytes().is_none());
+
+        let mut reader = vfs.open("big").unwrap();
+        let mapped = reader.mapped_bytes().expect("Should be mapped");
+        assert_eq!((*mapped).as_ref(), b"0123456789");
+        assert_eq!(reader.get_size().unwrap(), 10);
+
+        // The mapping can still be used as a reader.
+        reader.seek(SeekFrom::Start(5)).unwrap();
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "56789");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_strict_confinement() {
+        use std::os::unix::fs::symlink;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let outside = tmp_dir.path().join("outside");
+        std::fs::write(&outside, "secret").unwrap();
+        let root = tmp_dir.path().join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir").join("a"), "aaaa").unwrap();
+
+        // Links out of the root, directly and via a directory.
+        symlink(&outside, root.join("escape")).unwrap();
+        symlink(tmp_dir.path(), root.join("escape_dir")).unwrap();
+        symlink("../../outside", root.join("dir").join("relative_escape")).unwrap();
+        // And links which stay inside it.
+        symlink("dir/a", root.join("inside")).unwrap();
+        symlink("../dir", root.join("dir").join("loop_back")).unwrap();
+        symlink(root.join("dir").join("a"), root.join("absolute_inside")).unwrap();
+
+        let vfs = FilesystemVfs::with_confinement(&root, Confinement::Strict).unwrap();
+        let read = |key: &str| -> Result<String> {
+            let mut out = String::new();
+            vfs.open(key)?.read_to_string(&mut out)?;
+            Ok(out)
+        };
+
+        assert_eq!(read("dir/a").unwrap(), "aaaa");
+        assert_eq!(read("inside").unwrap(), "aaaa");
+        assert_eq!(read("absolute_inside").unwrap(), "aaaa");
+        assert_eq!(read("dir/loop_back/a").unwrap(), "aaaa");
+        assert_eq!(read("dir/../dir/./a").unwrap(), "aaaa");
+        // A leading root means the root of the VFS, as it does in the default mode.
+        assert_eq!(read("/dir/a").unwrap(), "aaaa");
+        assert_eq!(read("missing").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(read("/etc/passwd").unwrap_err().kind(), ErrorKind::NotFound);
+
+        for key in [
+            "escape",
+            "escape_dir/outside",
+            "dir/relative_escape",
+            "../outside",
+            "dir/../../outside",
+            "/../outside",
+        ] {
+            let err = read(key).unwrap_err();
+            assert!(is_root_escape(&err), "{}: {:?}", key, err);
+        }
+
+        // The default mode follows the symlink, which is exactly the problem.
+        let logical = FilesystemVfs::new(&root).unwrap();
+        assert!(logical.open("escape").is_ok());
+
+        // Listing skips links which escape rather than failing.
+        let mut listed = vfs.list("").unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["absolute_inside", "dir/a", "inside"]);
+        assert!(is_root_escape(&vfs.metadata("escape").unwrap_err()));
+    }
 }
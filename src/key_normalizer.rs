@@ -0,0 +1,139 @@
+//! Normalization of keys, so that different spellings of the same key share one cache entry.
+//!
+//! An [AssetCache](crate::AssetCache) given a [KeyNormalizer] with
+//! [AssetCache::with_key_normalizer](crate::AssetCache::with_key_normalizer) runs every key through it before looking
+//! anything up, and the normalized key is also what gets handed to the [Vfs](crate::Vfs).
+use std::borrow::Cow;
+
+/// Converts keys to a canonical form.
+///
+/// Two keys which refer to the same asset should normalize to the same string.  Normalization should be idempotent.
+pub trait KeyNormalizer: Send + Sync + 'static {
+    fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str>;
+}
+
+/// The default [KeyNormalizer], which treats keys as `/`-separated paths.
+///
+/// This strips leading, trailing and repeated slashes, drops `.` segments, and resolves `..` against the segment
+/// before it, so `a/b.png`, `/a/b.png` and `a/./c/../b.png` all become `a/b.png`.  A `..` which would go above the
+/// start of the key is kept, so that the [Vfs](crate::Vfs) can reject it.  Optionally, keys can also be lowercased for
+/// case-insensitive filesystems.
+///
+/// This is only suitable for keys which are paths: a key like `http://host/a` loses its double slash.  `..` is resolved
+/// lexically, so `link/../a` becomes `a` even if `link` is a symlink to a directory elsewhere, where
+/// [Confinement::Strict](crate::Confinement::Strict) would look for `a` next to the link's target.  Avoid `..` in keys
+/// for such trees.
+///
+/// Keys which are already normal are returned without allocating.
+#[derive(Clone, Debug, Default)]
+pub struct DefaultKeyNormalizer {
+    /// Lowercase keys as well.
+    pub case_fold: bool,
+}
+
+impl DefaultKeyNormalizer {
+    fn is_normalized(&self, key: &str) -> bool {
+        if key.is_empty() {
+            return true;
+        }
+
+        // Checking for uppercase isn't enough: titlecase characters such as `ǅ` also change.
+        if self.case_fold && key.chars().any(|c| !lowercases_to_itself(c)) {
+            return false;
+        }
+
+        let mut seen_normal = false;
+        for seg in key.split('/') {
+            match seg {
+                "" | "." => return false,
+                ".." if seen_normal => return false,
+                ".." => {}
+                _ => seen_normal = true,
+            }
+        }
+
+        true
+    }
+}
+
+fn lowercases_to_itself(c: char) -> bool {
+    let mut lower = c.to_lowercase();
+    lower.next() == Some(c) && lower.next().is_none()
+}
+
+impl KeyNormalizer for DefaultKeyNormalizer {
+    fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if self.is_normalized(key) {
+            return Cow::Borrowed(key);
+        }
+
+        let mut segments: Vec<&str> = vec![];
+        for seg in key.split('/') {
+            match seg {
+                "" | "." => {}
+                ".." => match segments.last() {
+                    Some(&l) if l != ".." => {
+                        segments.pop();
+                    }
+                    _ => segments.push(".."),
+                },
+                _ => segments.push(seg),
+            }
+        }
+
+        let joined = segments.join("/");
+        if self.case_fold {
+            Cow::Owned(joined.to_lowercase())
+        } else {
+            Cow::Owned(joined)
+        }
+    }
+}
+
+/// Normalize with the default settings, for VFSes which store normalized keys.
+pub(crate) fn normalize_default(key: &str) -> Cow<'_, str> {
+    DefaultKeyNormalizer::default().normalize(key)
+}
+
+/// Does the normalized `key` live under the normalized directory `prefix`?
+///
+/// This is the [Vfs::list](crate::Vfs::list) notion of a prefix, for VFSes which store flat keys.
+pub(crate) fn is_under_prefix(key: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || (key.len() > prefix.len()
+            && key.starts_with(prefix)
+            && key.as_bytes()[prefix.len()] == b'/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_normalizer() {
+        let norm = DefaultKeyNormalizer::default();
+        for k in &[
+            "a/b.png",
+            "/a/b.png",
+            "a//b.png/",
+            "a/./c/../b.png",
+            "./a/c/d/../../b.png",
+        ] {
+            assert_eq!(norm.normalize(k), "a/b.png", "{}", k);
+        }
+
+        assert!(matches!(norm.normalize("a/b.png"), Cow::Borrowed(_)));
+        assert_eq!(norm.normalize("../d"), "../d");
+        assert_eq!(norm.normalize("a/../../d"), "../d");
+        assert_eq!(norm.normalize("A/B.png"), "A/B.png");
+
+        let folding = DefaultKeyNormalizer { case_fold: true };
+        assert_eq!(folding.normalize("/A/./B.png"), "a/b.png");
+        assert!(matches!(folding.normalize("a/b.png"), Cow::Borrowed(_)));
+        for k in &["ǅ/x", "ΑΣ", "İ"] {
+            let once = folding.normalize(k).into_owned();
+            assert_eq!(folding.normalize(&once), once, "{}", k);
+        }
+        assert_eq!(folding.normalize("ǅ"), "ǆ");
+    }
+}
@@ -0,0 +1,184 @@
+use std::io::{ErrorKind, Read, Result, Seek, SeekFrom};
+
+use crate::*;
+
+/// A [Vfs] which looks keys up in `Upper` first, falling back to `Lower` for anything `Upper` doesn't have.
+///
+/// For example, put a [FilesystemVfs] over an [EmbeddedVfs] so that files on disk override defaults baked into the
+/// binary.  Only [ErrorKind::NotFound] falls through to the lower layer; other errors from the upper layer are
+/// returned as-is.  The exception is the optional queries: a layer which returns [ErrorKind::Unsupported] from
+/// [Vfs::list] or [Vfs::exists] is skipped.  Layers nest, so more than two can be stacked.
+#[derive(Debug)]
+pub struct LayeredVfs<Upper, Lower> {
+    upper: Upper,
+    lower: Lower,
+}
+
+/// The reader for a [LayeredVfs], from whichever layer the key was found in.
+#[derive(Debug)]
+pub enum LayeredReader<U, L> {
+    Upper(U),
+    Lower(L),
+}
+
+impl<Upper, Lower> LayeredVfs<Upper, Lower> {
+    pub fn new(upper: Upper, lower: Lower) -> LayeredVfs<Upper, Lower> {
+        LayeredVfs { upper, lower }
+    }
+
+    pub fn upper(&self) -> &Upper {
+        &self.upper
+    }
+
+    pub fn lower(&self) -> &Lower {
+        &self.lower
+    }
+}
+
+/// Run an operation against the upper layer, then the lower layer if the upper one doesn't have the key.
+fn with_fallback<T>(upper: Result<T>, lower: impl FnOnce() -> Result<T>) -> Result<T> {
+    match upper {
+        Err(e) if e.kind() == ErrorKind::NotFound => lower(),
+        x => x,
+    }
+}
+
+impl<K: ?Sized, Upper: Vfs<K>, Lower: Vfs<K>> Vfs<K> for LayeredVfs<Upper, Lower> {
+    type Reader = LayeredReader<Upper::Reader, Lower::Reader>;
+
+    fn open(&self, key: &K) -> Result<Self::Reader> {
+        with_fallback(self.upper.open(key).map(LayeredReader::Upper), || {
+            self.lower.open(key).map(LayeredReader::Lower)
+        })
+    }
+
+    /// Lists the union of both layers.  A layer which doesn't have the prefix or doesn't support listing is skipped,
+    /// unless neither layer can list it.
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let skippable =
+            |e: &std::io::Error| matches!(e.kind(), ErrorKind::NotFound | ErrorKind::Unsupported);
+
+        let (upper, lower) = (self.upper.list(prefix), self.lower.list(prefix));
+        let mut keys = match (upper, lower) {
+            (Ok(mut u), Ok(l)) => {
+                u.extend(l);
+                u
+            }
+            (Ok(x), Err(e)) | (Err(e), Ok(x)) if skippable(&e) => x,
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        };
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// True if either layer has the key.  A layer which doesn't support the query is skipped, unless neither does.
+    fn exists(&self, key: &K) -> Result<bool> {
+        match self.upper.exists(key) {
+            Ok(true) => Ok(true),
+            Ok(false) => self.lower.exists(key),
+            Err(e) if e.kind() == ErrorKind::Unsupported => match self.lower.exists(key) {
+                Err(l) if l.kind() == ErrorKind::Unsupported => Err(e),
+                x => x,
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn metadata(&self, key: &K) -> Result<VfsMetadata> {
+        with_fallback(self.upper.metadata(key), || self.lower.metadata(key))
+    }
+}
+
+impl<U: Read, L: Read> Read for LayeredReader<U, L> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            LayeredReader::Upper(x) => x.read(buf),
+            LayeredReader::Lower(x) => x.read(buf),
+        }
+    }
+}
+
+impl<U: Seek, L: Seek> Seek for LayeredReader<U, L> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match self {
+            LayeredReader::Upper(x) => x.seek(pos),
+            LayeredReader::Lower(x) => x.seek(pos),
+        }
+    }
+}
+
+impl<U: VfsReader, L: VfsReader> VfsReader for LayeredReader<U, L> {
+    fn get_size(&self) -> Result<u64> {
+        match self {
+            LayeredReader::Upper(x) => x.get_size(),
+            LayeredReader::Lower(x) => x.get_size(),
+        }
+    }
+
+    fn mapped_bytes(&self) -> Option<SharedBytes> {
+        match self {
+            LayeredReader::Upper(x) => x.mapped_bytes(),
+            LayeredReader::Lower(x) => x.mapped_bytes(),
+        }
+    }
+
+    fn content_hash(&self) -> Option<u64> {
+        match self {
+            LayeredReader::Upper(x) => x.content_hash(),
+            LayeredReader::Lower(x) => x.content_hash(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DEFAULTS: &[(&str, &[u8])] = &[("a", b"embedded a"), ("b", b"embedded b")];
+
+    #[test]
+    fn test_layered_vfs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("a"), "disk a").unwrap();
+        std::fs::write(tmp_dir.path().join("c"), "disk c").unwrap();
+
+        let vfs = LayeredVfs::new(
+            FilesystemVfs::new(tmp_dir.path()).unwrap(),
+            EmbeddedVfs::new(DEFAULTS),
+        );
+        let read = |key: &str| {
+            let mut out = String::new();
+            vfs.open(key).unwrap().read_to_string(&mut out).unwrap();
+            out
+        };
+
+        assert_eq!(read("a"), "disk a");
+        assert_eq!(read("b"), "embedded b");
+        assert_eq!(read("c"), "disk c");
+        assert!(matches!(vfs.open("a").unwrap(), LayeredReader::Upper(_)));
+        assert_eq!(vfs.open("d").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(vfs.list("").unwrap(), vec!["a", "b", "c"]);
+        assert!(vfs.exists("b").unwrap());
+        assert_eq!(vfs.metadata("b").unwrap().size, 10);
+
+        // A layer which can't answer existence queries is skipped, like it is for listing.
+        let vfs = LayeredVfs::new(OpenOnly, EmbeddedVfs::new(DEFAULTS));
+        assert!(vfs.exists("b").unwrap());
+        assert!(!vfs.exists("d").unwrap());
+        assert!(vfs.list("").is_ok());
+        let vfs = LayeredVfs::new(OpenOnly, OpenOnly);
+        assert_eq!(vfs.exists("b").unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    /// Supports nothing but opening, and has nothing to open.
+    struct OpenOnly;
+
+    impl Vfs for OpenOnly {
+        type Reader = std::io::Cursor<&'static [u8]>;
+
+        fn open(&self, _key: &str) -> Result<Self::Reader> {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+}
@@ -23,15 +23,78 @@
 //!
 //! To use this crate, implement the [Vfs] and [Decoder] traits, then construct a [AssetCache] with your chosen
 //! [AssetCacheConfig].  For simpler usage with a filesystem directory, use [FilesystemVfs], which does this for you.
+//! For assets which are generated at runtime, use [MemoryVfs], and for assets baked into the binary, use [EmbeddedVfs].
+//! [LayeredVfs] stacks VFSes, for example to let files on disk override embedded defaults.  With the `gzip` or `zstd`
+//! features, `DecompressingVfs` transparently decompresses files stored compressed.
+//!
+//! To keep several caches, for example one per asset type, under one total budget, put them in a [CacheGroup].
+//!
+//! Caches can be shrunk on demand with [AssetCache::shrink_to], or automatically under memory pressure with a
+//! [MemoryMonitor].
+//!
+//! Decodes which are too slow to redo on every start can be persisted across runs with
+//! [AssetCache::with_disk_tier].
+//!
+//! Items can be tagged, for example by the level which uses them, and then evicted or pinned by tag.  See
+//! [AssetCache::get_tagged].
+//!
+//! Applications which move between scenes can give the cache a manifest of each scene's assets with
+//! [AssetCache::transition_to], which loads the new ones in the background and releases the old ones afterwards.
+//!
+//! To follow assets across hot reloads, use [AssetCache::get_handle], which gives an [AssetHandle] that always loads
+//! the latest version published by [AssetCache::replace] or [AssetCache::reload].
+//!
+//! Things computed from assets, such as collision meshes from models, can be memoized with a [DerivedCache], which
+//! drops them when their source is removed or reloaded.
+//!
+//! Keys can be normalized before use with [AssetCache::with_key_normalizer], so that `a/b.png` and `/a/./b.png`
+//! share one cache entry.  See [KeyNormalizer].
 //!
 //! A blanket impl of [Vfs] is provided for [std::sync::Arc] so that any Arc to a Vfs is itself a Vfs.  This allows for
 //! sharing a Vfs between caches or anything else that might need it.
 mod asset_cache;
+mod asset_handle;
+mod bytes_compression;
+mod cache_group;
+mod cache_key;
+mod confined_open;
 mod cost_based_lru;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+mod decompressing_vfs;
+mod derived_cache;
+mod disk_tier;
+mod embedded_vfs;
 mod filesystem_vfs;
+mod key_normalizer;
+mod layered_vfs;
+mod memory_monitor;
+mod memory_vfs;
+mod shared_bytes;
+mod stable_hash;
+mod tag_index;
+#[cfg(test)]
+mod test_util;
 mod traits;
+mod transition;
 
 pub use asset_cache::*;
+pub use asset_handle::*;
+pub use bytes_compression::*;
+pub use cache_group::*;
+pub use cache_key::*;
+pub use confined_open::*;
 pub use cost_based_lru::*;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use decompressing_vfs::*;
+pub use derived_cache::*;
+pub use disk_tier::*;
+pub use embedded_vfs::*;
 pub use filesystem_vfs::*;
+pub use key_normalizer::*;
+pub use layered_vfs::*;
+pub use memory_monitor::*;
+pub use memory_vfs::*;
+pub use shared_bytes::*;
+pub(crate) use stable_hash::*;
 pub use traits::*;
+pub use transition::*;
@@ -0,0 +1,431 @@
+//! Shrinking caches automatically when the system runs low on memory.
+//!
+//! A [MemoryMonitor] polls a [MemorySource] on a background thread, and whenever memory use crosses a threshold, calls
+//! [AssetCache::shrink_to](crate::AssetCache::shrink_to) on every cache registered with it.  It then waits for memory
+//! use to fall back below a lower threshold before shrinking again, so that pressure from outside the caches doesn't
+//! empty them.  Two sources are provided
+//! for Linux: [ProcMeminfo] for the whole machine, and [CgroupMemory] for the cgroup (v2) the process runs in, which
+//! is usually what matters in containers.  Anything else can be plugged in by implementing [MemorySource], including
+//! closures.
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+/// How much memory is in use, out of how much is available to us.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemorySample {
+    pub used: u64,
+    pub limit: u64,
+}
+
+impl MemorySample {
+    /// The fraction of the limit which is in use.
+    pub fn pressure(&self) -> f64 {
+        if self.limit == 0 {
+            return 1.0;
+        }
+        self.used as f64 / self.limit as f64
+    }
+}
+
+/// Somewhere to read memory usage from.
+pub trait MemorySource: Send + 'static {
+    fn sample(&mut self) -> Result<MemorySample>;
+}
+
+impl<F: FnMut() -> Result<MemorySample> + Send + 'static> MemorySource for F {
+    fn sample(&mut self) -> Result<MemorySample> {
+        self()
+    }
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, what.to_string())
+}
+
+/// Machine-wide memory usage from `/proc/meminfo`: `MemTotal - MemAvailable` out of `MemTotal`.
+#[derive(Debug)]
+pub struct ProcMeminfo {
+    path: PathBuf,
+}
+
+impl ProcMeminfo {
+    pub fn new() -> ProcMeminfo {
+        ProcMeminfo::from_path("/proc/meminfo".as_ref())
+    }
+
+    /// Read a file in the format of `/proc/meminfo` from somewhere else.
+    pub fn from_path(path: &Path) -> ProcMeminfo {
+        ProcMeminfo {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Default for ProcMeminfo {
+    fn default() -> ProcMeminfo {
+        ProcMeminfo::new()
+    }
+}
+
+impl MemorySource for ProcMeminfo {
+    fn sample(&mut self) -> Result<MemorySample> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let field = |name: &str| -> Result<u64> {
+            let line = contents
+                .lines()
+                .find(|l| l.split(':').next() == Some(name))
+                .ok_or_else(|| invalid("Missing field in meminfo"))?;
+            let kb = line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|x| x.parse::<u64>().ok())
+                .ok_or_else(|| invalid("Malformed meminfo line"))?;
+            Ok(kb * 1024)
+        };
+
+        let total = field("MemTotal")?;
+        let available = field("MemAvailable")?;
+        Ok(MemorySample {
+            used: total.saturating_sub(available),
+            limit: total,
+        })
+    }
+}
+
+/// Usage of a cgroup v2, from its `memory.current` and `memory.max`.
+///
+/// A cgroup without a limit reports `max`, in which case this falls back to the machine's total from
+/// `/proc/meminfo`.
+#[derive(Debug)]
+pub struct CgroupMemory {
+    dir: PathBuf,
+    fallback: ProcMeminfo,
+}
+
+impl CgroupMemory {
+    /// Use the cgroup of the current process, as mounted under `/sys/fs/cgroup`.
+    pub fn new() -> Result<CgroupMemory> {
+        let cgroup = std::fs::read_to_string("/proc/self/cgroup")?;
+        // On a pure v2 system, this is a single line of the form `0::/path`.
+        let path = cgroup
+            .lines()
+            .find_map(|l| l.strip_prefix("0::"))
+            .ok_or_else(|| invalid("Not in a cgroup v2 hierarchy"))?;
+        Ok(CgroupMemory::from_dir(
+            &Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')),
+        ))
+    }
+
+    /// Use the cgroup with the given directory.
+    pub fn from_dir(dir: &Path) -> CgroupMemory {
+        CgroupMemory {
+            dir: dir.to_path_buf(),
+            fallback: ProcMeminfo::new(),
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<String> {
+        Ok(std::fs::read_to_string(self.dir.join(name))?
+            .trim()
+            .to_string())
+    }
+}
+
+impl MemorySource for CgroupMemory {
+    fn sample(&mut self) -> Result<MemorySample> {
+        let parse = |x: &str| {
+            x.parse::<u64>()
+                .map_err(|_| invalid("Malformed cgroup memory file"))
+        };
+        let used = parse(&self.read("memory.current")?)?;
+        let max = self.read("memory.max")?;
+        let limit = if max == "max" {
+            self.fallback.sample()?.limit
+        } else {
+            parse(&max)?
+        };
+        Ok(MemorySample { used, limit })
+    }
+}
+
+/// Something a [MemoryMonitor] can shrink.
+pub(crate) trait ShrinkTarget: Send + Sync {
+    fn shrink_to(&self, fraction: f64);
+}
+
+impl<K: crate::CacheKey + ?Sized, O: Send + Sync> ShrinkTarget for crate::asset_cache::Tiers<K, O> {
+    fn shrink_to(&self, fraction: f64) {
+        crate::asset_cache::Tiers::shrink_to(self, fraction)
+    }
+}
+
+/// Configuration for a [MemoryMonitor].
+#[derive(Clone, Debug, derive_builder::Builder)]
+pub struct MemoryMonitorConfig {
+    /// The [MemorySample::pressure] at or above which caches are shrunk.  Defaults to 0.9.
+    #[builder(default = "0.9")]
+    pub high_pressure: f64,
+    /// Once caches have been shrunk, the pressure must fall below this before they are shrunk again.  Defaults to 0.8.
+    ///
+    /// Values above [MemoryMonitorConfig::high_pressure] behave like it, so caches are shrunk once per poll which
+    /// crosses the threshold from below.
+    #[builder(default = "0.8")]
+    pub low_pressure: f64,
+    /// The fraction of their contents caches keep each time they are shrunk.  Defaults to 0.5.
+    #[builder(default = "0.5")]
+    pub shrink_fraction: f64,
+    /// How often to check.  Defaults to one second.
+    #[builder(default = "Duration::from_secs(1)")]
+    pub poll_interval: Duration,
+}
+
+struct MonitorShared {
+    config: MemoryMonitorConfig,
+    source: Mutex<Box<dyn MemorySource>>,
+    targets: Mutex<Vec<Weak<dyn ShrinkTarget>>>,
+    /// Whether pressure has been low since the last shrink.
+    armed: Mutex<bool>,
+    stopping: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl MonitorShared {
+    fn check(&self) -> Result<bool> {
+        let pressure = self.source.lock().unwrap().sample()?.pressure();
+        let mut armed = self.armed.lock().unwrap();
+        if pressure < self.config.low_pressure.min(self.config.high_pressure) {
+            *armed = true;
+        }
+        if pressure < self.config.high_pressure || !*armed {
+            return Ok(false);
+        }
+        *armed = false;
+        drop(armed);
+
+        let targets = {
+            let mut targets = self.targets.lock().unwrap();
+            targets.retain(|t| t.strong_count() > 0);
+            targets
+                .iter()
+                .filter_map(|t| t.upgrade())
+                .collect::<Vec<_>>()
+        };
+        for t in targets {
+            t.shrink_to(self.config.shrink_fraction);
+        }
+        Ok(true)
+    }
+}
+
+/// Watches memory usage on a background thread, and shrinks registered caches when it gets too high.
+///
+/// Register caches with [AssetCache::with_memory_monitor](crate::AssetCache::with_memory_monitor).  Caches are held
+/// weakly.  Dropping the monitor stops the thread.
+pub struct MemoryMonitor {
+    shared: Arc<MonitorShared>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MemoryMonitor {
+    /// Start a monitor polling the given source.
+    pub fn start(source: impl MemorySource, config: MemoryMonitorConfig) -> MemoryMonitor {
+        let shared = Arc::new(MonitorShared {
+            config,
+            source: Mutex::new(Box::new(source)),
+            targets: Mutex::new(vec![]),
+            armed: Mutex::new(true),
+            stopping: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+
+        let thread_shared = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("asset_lru memory monitor".into())
+            .spawn(move || {
+                let shared = thread_shared;
+                loop {
+                    // Waiting with a predicate checks it first, so we can't miss a stop which happened before we got
+                    // here.
+                    let stopping = shared
+                        .wakeup
+                        .wait_timeout_while(
+                            shared.stopping.lock().unwrap(),
+                            shared.config.poll_interval,
+                            |stopping| !*stopping,
+                        )
+                        .unwrap()
+                        .0;
+                    if *stopping {
+                        return;
+                    }
+                    drop(stopping);
+                    // Failing to read is probably permanent, but there's nobody to report it to, and the caches work
+                    // fine without us.
+                    let _ = shared.check();
+                }
+            })
+            .expect("Should be able to spawn the memory monitor thread");
+
+        MemoryMonitor {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Check memory usage now rather than waiting for the next poll, returning whether caches were shrunk.
+    ///
+    /// Like a poll, this doesn't shrink again until pressure has dropped below [MemoryMonitorConfig::low_pressure].
+    pub fn check(&self) -> Result<bool> {
+        self.shared.check()
+    }
+
+    pub(crate) fn register(&self, target: Weak<dyn ShrinkTarget>) {
+        self.shared.targets.lock().unwrap().push(target);
+    }
+}
+
+impl Drop for MemoryMonitor {
+    fn drop(&mut self) {
+        *self.shared.stopping.lock().unwrap() = true;
+        self.shared.wakeup.notify_all();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryMonitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryMonitor")
+            .field("config", &self.shared.config)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingTarget(AtomicUsize);
+
+    impl ShrinkTarget for CountingTarget {
+        fn shrink_to(&self, _fraction: f64) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// A monitor over a pressure we control, in percent, with one registered target.
+    fn fake_monitor(
+        poll_interval: Duration,
+    ) -> (MemoryMonitor, Arc<AtomicU64>, Arc<CountingTarget>) {
+        let pressure = Arc::new(AtomicU64::new(0));
+        let source_pressure = pressure.clone();
+        let monitor = MemoryMonitor::start(
+            move || {
+                Ok(MemorySample {
+                    used: source_pressure.load(Ordering::SeqCst),
+                    limit: 100,
+                })
+            },
+            MemoryMonitorConfigBuilder::default()
+                .poll_interval(poll_interval)
+                .build()
+                .unwrap(),
+        );
+        let target = Arc::new(CountingTarget::default());
+        let weak: Weak<dyn ShrinkTarget> = Arc::downgrade(&target) as _;
+        monitor.register(weak);
+        (monitor, pressure, target)
+    }
+
+    #[test]
+    fn test_check_hysteresis() {
+        let (monitor, pressure, target) = fake_monitor(Duration::from_secs(3600));
+        assert!(!monitor.check().unwrap());
+
+        // Sustained pressure only shrinks once.
+        pressure.store(95, Ordering::SeqCst);
+        assert!(monitor.check().unwrap());
+        assert!(!monitor.check().unwrap());
+        assert_eq!(target.0.load(Ordering::SeqCst), 1);
+
+        // Dipping below the high threshold isn't enough to shrink again...
+        pressure.store(85, Ordering::SeqCst);
+        assert!(!monitor.check().unwrap());
+        pressure.store(95, Ordering::SeqCst);
+        assert!(!monitor.check().unwrap());
+
+        // ...but going below the low one is.
+        pressure.store(50, Ordering::SeqCst);
+        assert!(!monitor.check().unwrap());
+        pressure.store(95, Ordering::SeqCst);
+        assert!(monitor.check().unwrap());
+        assert_eq!(target.0.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_monitor_thread() {
+        let (monitor, pressure, target) = fake_monitor(Duration::from_millis(1));
+        let wait_for = |count| {
+            let start = std::time::Instant::now();
+            while target.0.load(Ordering::SeqCst) < count {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        pressure.store(95, Ordering::SeqCst);
+        wait_for(1);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(target.0.load(Ordering::SeqCst), 1);
+
+        pressure.store(0, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        pressure.store(95, Ordering::SeqCst);
+        wait_for(2);
+        drop(monitor);
+    }
+
+    #[test]
+    fn test_proc_meminfo() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("meminfo");
+        std::fs::write(
+            &path,
+            "MemTotal:       1000 kB\nMemFree:         100 kB\nMemAvailable:    250 kB\n",
+        )
+        .unwrap();
+        let sample = ProcMeminfo::from_path(&path).sample().unwrap();
+        assert_eq!(
+            sample,
+            MemorySample {
+                used: 750 * 1024,
+                limit: 1000 * 1024
+            }
+        );
+        assert_eq!(sample.pressure(), 0.75);
+
+        std::fs::write(&path, "MemTotal: 1000 kB\n").unwrap();
+        assert!(ProcMeminfo::from_path(&path).sample().is_err());
+    }
+
+    #[test]
+    fn test_cgroup_memory() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("memory.current"), "300\n").unwrap();
+        std::fs::write(tmp_dir.path().join("memory.max"), "400\n").unwrap();
+        let sample = CgroupMemory::from_dir(tmp_dir.path()).sample().unwrap();
+        assert_eq!(
+            sample,
+            MemorySample {
+                used: 300,
+                limit: 400
+            }
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! [MemoryVfs], and [VfsReader] impls for cursors over shared and static buffers, so that other VFSes which keep their
+//! content in memory can hand it out without copying.
+use std::io::{Cursor, Error, ErrorKind, Result};
+use std::sync::{Arc, RwLock};
+
+use crate::*;
+
+struct MemoryEntry {
+    data: Arc<[u8]>,
+    version: u64,
+}
+
+#[derive(Default)]
+struct MemoryVfsState {
+    entries: std::collections::HashMap<String, MemoryEntry, ahash::RandomState>,
+}
+
+impl MemoryEntry {
+    fn new(data: Arc<[u8]>) -> MemoryEntry {
+        MemoryEntry {
+            version: content_version(&data),
+            data,
+        }
+    }
+}
+
+/// A thread-safe VFS which serves byte buffers from memory.
+///
+/// Useful for procedurally generated assets and for tests.  Keys are normalized with [DefaultKeyNormalizer], and
+/// readers share the stored buffer rather than copying it.  The [VfsMetadata::version] of an entry is a hash of its
+/// content, computed when it is inserted, so versions change with the content and are the same across runs.
+#[derive(Default)]
+pub struct MemoryVfs {
+    state: RwLock<MemoryVfsState>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> MemoryVfs {
+        Default::default()
+    }
+
+    /// Insert an entry, returning the old data if the key was already present.
+    pub fn insert(&self, key: &str, data: impl Into<Arc<[u8]>>) -> Option<Arc<[u8]>> {
+        let entry = MemoryEntry::new(data.into());
+        self.state
+            .write()
+            .unwrap()
+            .entries
+            .insert(normalize_default(key).into_owned(), entry)
+            .map(|e| e.data)
+    }
+
+    /// Replace the data of an entry which is already present, returning the old data.
+    ///
+    /// Does nothing and returns `None` if the key isn't present.
+    pub fn replace(&self, key: &str, data: impl Into<Arc<[u8]>>) -> Option<Arc<[u8]>> {
+        let new = MemoryEntry::new(data.into());
+        let mut state = self.state.write().unwrap();
+        let entry = state.entries.get_mut(&*normalize_default(key))?;
+        Some(std::mem::replace(entry, new).data)
+    }
+
+    /// Remove an entry, returning its data if it was present.
+    pub fn remove(&self, key: &str) -> Option<Arc<[u8]>> {
+        self.state
+            .write()
+            .unwrap()
+            .entries
+            .remove(&*normalize_default(key))
+            .map(|e| e.data)
+    }
+}
+
+impl Vfs for MemoryVfs {
+    type Reader = Cursor<Arc<[u8]>>;
+
+    fn open(&self, key: &str) -> Result<Self::Reader> {
+        let state = self.state.read().unwrap();
+        let entry = state
+            .entries
+            .get(&*normalize_default(key))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Entry not found"))?;
+        Ok(Cursor::new(entry.data.clone()))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = normalize_default(prefix);
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .entries
+            .keys()
+            .filter(|k| is_under_prefix(k, &prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        match self.metadata(key) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keys which only exist as the prefix of other keys are reported as directories.
+    fn metadata(&self, key: &str) -> Result<VfsMetadata> {
+        let key = normalize_default(key);
+        let state = self.state.read().unwrap();
+        if let Some(e) = state.entries.get(&*key) {
+            return Ok(VfsMetadata {
+                size: e.data.len() as u64,
+                version: Some(e.version),
+                is_dir: false,
+            });
+        }
+
+        if state.entries.keys().any(|k| is_under_prefix(k, &key)) {
+            return Ok(VfsMetadata {
+                size: 0,
+                version: None,
+                is_dir: true,
+            });
+        }
+
+        Err(Error::new(ErrorKind::NotFound, "Entry not found"))
+    }
+}
+
+impl VfsReader for Cursor<Arc<[u8]>> {
+    fn get_size(&self) -> Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn mapped_bytes(&self) -> Option<SharedBytes> {
+        Some(SharedBytes::new(self.get_ref().clone()))
+    }
+}
+
+impl VfsReader for Cursor<&'static [u8]> {
+    fn get_size(&self) -> Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+
+    fn mapped_bytes(&self) -> Option<SharedBytes> {
+        Some(SharedBytes::new(*self.get_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_vfs() {
+        let vfs = MemoryVfs::new();
+        assert!(vfs.insert("/sfx/a", &b"aaa"[..]).is_none());
+        vfs.insert("sfx/steps/b", vec![1, 2]);
+        vfs.insert("sfx_old", &b"old"[..]);
+
+        let mut out = String::new();
+        vfs.open("sfx/./a")
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "aaa");
+        assert_eq!(vfs.open("missing").unwrap_err().kind(), ErrorKind::NotFound);
+
+        // Readers share the stored buffer.
+        let r1 = vfs.open("sfx/a").unwrap();
+        let r2 = vfs.open("sfx/a").unwrap();
+        assert!(Arc::ptr_eq(r1.get_ref(), r2.get_ref()));
+
+        let mut listed = vfs.list("sfx").unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["sfx/a", "sfx/steps/b"]);
+        assert_eq!(vfs.list("").unwrap().len(), 3);
+
+        let meta = vfs.metadata("sfx/a").unwrap();
+        assert_eq!(meta.size, 3);
+        assert!(vfs.metadata("sfx/steps").unwrap().is_dir);
+        assert!(vfs.exists("sfx").unwrap());
+        assert!(!vfs.exists("sf").unwrap());
+
+        // Replacing changes the version, and only works on existing keys.
+        assert!(vfs.replace("nope", &b""[..]).is_none());
+        assert!(!vfs.exists("nope").unwrap());
+        assert_eq!(&*vfs.replace("sfx/a", &b"new"[..]).unwrap(), b"aaa");
+        assert_ne!(vfs.metadata("sfx/a").unwrap().version, meta.version);
+
+        assert_eq!(&*vfs.remove("sfx/a").unwrap(), b"new");
+        assert!(!vfs.exists("sfx/a").unwrap());
+    }
+
+    struct SharedDecoder;
+
+    impl Decoder for SharedDecoder {
+        type Error = Error;
+        type Output = SharedBytes;
+
+        fn decode<R: Read>(&self, mut reader: R) -> Result<SharedBytes> {
+            let mut out = vec![];
+            reader.read_to_end(&mut out)?;
+            Ok(out.into())
+        }
+
+        fn estimate_cost(&self, item: &SharedBytes) -> Result<u64> {
+            Ok(item.len() as u64)
+        }
+
+        fn decode_shared(&self, bytes: SharedBytes) -> Result<SharedBytes> {
+            Ok(bytes)
+        }
+    }
+
+    #[test]
+    fn test_cache_shares_buffer() {
+        let vfs = Arc::new(MemoryVfs::new());
+        vfs.insert("a", &b"aaaa"[..]);
+        let source = vfs.open("a").unwrap().into_inner();
+
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(100)
+            .max_decoded_cost(0)
+            .max_single_object_decoded_cost(0)
+            .build()
+            .unwrap();
+        let cache = AssetCache::new(vfs, SharedDecoder, cfg);
+
+        // Both the cached bytes and the output decoded from them are the buffer the VFS holds.
+        let out = cache.get("a").unwrap();
+        assert_eq!(out.as_ptr(), source.as_ptr());
+        assert_eq!(cache.get("a").unwrap().as_ptr(), source.as_ptr());
+    }
+}
@@ -0,0 +1,131 @@
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::Arc;
+
+/// A cheaply clonable, immutable view of a shared byte buffer.
+///
+/// This is what the bytes tier of the [AssetCache](crate::AssetCache) stores, and what
+/// [Decoder::decode_shared](crate::Decoder::decode_shared) receives.  Cloning and slicing only bump a reference count,
+/// so decoded outputs can keep pieces of their input (for example sample data, or strings borrowed by a zero-copy
+/// parser) without copying it.  Note that anything kept this way keeps the whole buffer alive.
+///
+/// The buffer can be anything which is `AsRef<[u8]>`, such as a `Vec<u8>`, an `Arc<[u8]>`, or a memory mapping.
+#[derive(Clone)]
+pub struct SharedBytes {
+    buffer: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    start: usize,
+    end: usize,
+}
+
+impl SharedBytes {
+    pub fn new(buffer: impl AsRef<[u8]> + Send + Sync + 'static) -> SharedBytes {
+        SharedBytes::from_arc(Arc::new(buffer))
+    }
+
+    /// Share a buffer which is already behind an `Arc`.
+    pub fn from_arc(buffer: Arc<dyn AsRef<[u8]> + Send + Sync>) -> SharedBytes {
+        let end = (*buffer).as_ref().len();
+        SharedBytes {
+            buffer,
+            start: 0,
+            end,
+        }
+    }
+
+    /// Return a view of a subrange of these bytes, sharing the same buffer.
+    ///
+    /// Panics if the range is out of bounds, like slicing.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> SharedBytes {
+        let start = match range.start_bound() {
+            Bound::Included(x) => *x,
+            Bound::Excluded(x) => x + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(x) => x + 1,
+            Bound::Excluded(x) => *x,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(
+            start <= end && end <= self.len(),
+            "Range {}..{} out of bounds for SharedBytes of length {}",
+            start,
+            end,
+            self.len()
+        );
+
+        SharedBytes {
+            buffer: self.buffer.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+
+    /// Whether two views share the same underlying buffer.
+    pub fn shares_buffer_with(&self, other: &SharedBytes) -> bool {
+        Arc::ptr_eq(&self.buffer, &other.buffer)
+    }
+}
+
+impl Deref for SharedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.buffer).as_ref()[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for SharedBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedBytes")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl From<Vec<u8>> for SharedBytes {
+    fn from(v: Vec<u8>) -> SharedBytes {
+        SharedBytes::new(v)
+    }
+}
+
+impl From<Arc<[u8]>> for SharedBytes {
+    fn from(v: Arc<[u8]>) -> SharedBytes {
+        SharedBytes::new(v)
+    }
+}
+
+impl From<&'static [u8]> for SharedBytes {
+    fn from(v: &'static [u8]) -> SharedBytes {
+        SharedBytes::new(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_bytes() {
+        let bytes = SharedBytes::from(b"hello world".to_vec());
+        assert_eq!(&*bytes, b"hello world");
+
+        let world = bytes.slice(6..);
+        assert_eq!(&*world, b"world");
+        assert!(world.shares_buffer_with(&bytes));
+        assert_eq!(&*world.slice(1..=2), b"or");
+        assert_eq!(&*world.slice(..0), b"");
+        assert!(!bytes.shares_buffer_with(&SharedBytes::from(&b"hello"[..])));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slice_out_of_bounds() {
+        SharedBytes::from(b"abc".to_vec()).slice(1..4);
+    }
+}
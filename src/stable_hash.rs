@@ -0,0 +1,25 @@
+//! Hashing which, unlike the hasher used for the in-memory maps, gives the same result across runs and builds, for
+//! anything which outlives the process.
+
+/// 64-bit FNV-1a.
+pub(crate) struct Fnv(pub(crate) u64);
+
+impl Fnv {
+    pub(crate) fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// A version for a buffer, derived from its content, for VFSes which have nothing better.
+pub(crate) fn content_version(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv::new();
+    hasher.write(bytes);
+    hasher.0
+}
@@ -0,0 +1,92 @@
+//! Bookkeeping for the tags of an [AssetCache](crate::AssetCache).
+//!
+//! Tags live here rather than alongside cache entries, so that they survive eviction and apply again when the item is
+//! next decoded.
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::CacheKey;
+
+/// A many-to-many mapping between keys and tags.
+pub(crate) struct TagIndex<K: CacheKey + ?Sized> {
+    by_key: HashMap<Arc<K>, HashSet<String>, ahash::RandomState>,
+    by_tag: HashMap<String, HashSet<Arc<K>>, ahash::RandomState>,
+}
+
+impl<K: CacheKey + ?Sized> Default for TagIndex<K> {
+    fn default() -> Self {
+        TagIndex {
+            by_key: Default::default(),
+            by_tag: Default::default(),
+        }
+    }
+}
+
+impl<K: CacheKey + ?Sized> TagIndex<K> {
+    pub(crate) fn add(&mut self, key: &Arc<K>, tags: &[&str]) {
+        for tag in tags {
+            self.by_key
+                .entry(key.clone())
+                .or_default()
+                .insert(tag.to_string());
+            self.by_tag
+                .entry(tag.to_string())
+                .or_default()
+                .insert(key.clone());
+        }
+    }
+
+    /// The keys with a tag, in no particular order.
+    pub(crate) fn keys(&self, tag: &str) -> Vec<Arc<K>> {
+        self.by_tag
+            .get(tag)
+            .map(|k| k.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn tags(&self) -> impl Iterator<Item = &str> {
+        self.by_tag.keys().map(|x| x.as_str())
+    }
+
+    /// Forget every tag of a key.
+    pub(crate) fn remove_key(&mut self, key: &K) {
+        for tag in self.by_key.remove(key).into_iter().flatten() {
+            if let Some(keys) = self.by_tag.get_mut(&tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.by_tag.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_keys(index: &TagIndex<str>, tag: &str) -> Vec<String> {
+        let mut out = index
+            .keys(tag)
+            .iter()
+            .map(|k| k.to_string())
+            .collect::<Vec<_>>();
+        out.sort_unstable();
+        out
+    }
+
+    #[test]
+    fn test_tag_index() {
+        let mut index = TagIndex::<str>::default();
+        index.add(&"a".into(), &["level1", "common"]);
+        index.add(&"b".into(), &["level1"]);
+        index.add(&"b".into(), &["level1"]);
+        assert_eq!(sorted_keys(&index, "level1"), vec!["a", "b"]);
+        assert_eq!(sorted_keys(&index, "common"), vec!["a"]);
+        assert!(index.keys("missing").is_empty());
+
+        index.remove_key("a");
+        assert_eq!(sorted_keys(&index, "level1"), vec!["b"]);
+        assert_eq!(index.tags().collect::<Vec<_>>(), vec!["level1"]);
+    }
+}
@@ -0,0 +1,22 @@
+//! Fixtures shared by the tests of several modules.
+use std::io::{Error, Read};
+
+use crate::Decoder;
+
+/// Decodes the content as UTF-8, costing its length.
+pub(crate) struct StringDecoder;
+
+impl Decoder for StringDecoder {
+    type Error = Error;
+    type Output = String;
+
+    fn decode<R: Read>(&self, mut reader: R) -> Result<String, Error> {
+        let mut out = String::new();
+        reader.read_to_string(&mut out)?;
+        Ok(out)
+    }
+
+    fn estimate_cost(&self, item: &String) -> Result<u64, Error> {
+        Ok(item.len() as u64)
+    }
+}
@@ -1,18 +1,69 @@
-//! The [Vfs] trait is responsible for converting string keys to [Read] implementations.
+//! The [Vfs] trait is responsible for converting keys, usually strings, to [Read] implementations.
 //!
 //! The cache caches the bytes representation from whatever the [Vfs] returns, then uses a [Decoder] on it when needed
 //! to get the actual object.
-use std::io::{Error, Read, Seek};
+use std::io::{Error, ErrorKind, Read, Seek};
+
+use crate::SharedBytes;
+
+/// Metadata about an entry in a [Vfs], as returned by [Vfs::metadata].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VfsMetadata {
+    /// Size of the entry in bytes.  Zero for directories.
+    pub size: u64,
+    /// An opaque version which changes whenever the content of the entry changes, if the [Vfs] can provide one.
+    ///
+    /// For [FilesystemVfs](crate::FilesystemVfs) this is derived from the modification time.
+    pub version: Option<u64>,
+    /// Whether this entry is a directory rather than something which can be opened.
+    pub is_dir: bool,
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("This Vfs does not support {}", what),
+    )
+}
 
 /// "open" a "file" and return a [VfsReader] over it.
 ///
-/// This is the first step of the decoding process, and is used to get from a string key to a reader over some bytes to
-/// pass to the [Decoder].
-pub trait Vfs: Send + Sync + 'static {
+/// This is the first step of the decoding process, and is used to get from a key to a reader over some bytes to pass
+/// to the [Decoder].  Keys are strings by default, but can be any [CacheKey](crate::CacheKey), such as interned ids.
+/// Listing is in terms of string prefixes regardless, and only makes sense for string keys.
+pub trait Vfs<K: ?Sized = str>: Send + Sync + 'static {
     type Reader: VfsReader;
 
     /// Open a file.
-    fn open(&self, key: &str) -> Result<Self::Reader, Error>;
+    fn open(&self, key: &K) -> Result<Self::Reader, Error>;
+
+    /// List the keys of all entries under the given prefix, recursively.
+    ///
+    /// The prefix is treated as a directory: `sfx/footsteps` lists `sfx/footsteps/a.wav` and
+    /// `sfx/footsteps/grass/b.wav`, but not `sfx/footsteps_old.wav`.  The empty prefix lists everything.  Directories
+    /// themselves aren't returned, and the order of the returned keys is unspecified.
+    ///
+    /// The default implementation fails with [ErrorKind::Unsupported].
+    fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let _ = prefix;
+        Err(unsupported("listing"))
+    }
+
+    /// Return whether the given key exists, without opening it.
+    ///
+    /// The default implementation fails with [ErrorKind::Unsupported].
+    fn exists(&self, key: &K) -> Result<bool, Error> {
+        let _ = key;
+        Err(unsupported("existence checks"))
+    }
+
+    /// Get metadata for the given key, without opening it.
+    ///
+    /// The default implementation fails with [ErrorKind::Unsupported].
+    fn metadata(&self, key: &K) -> Result<VfsMetadata, Error> {
+        let _ = key;
+        Err(unsupported("metadata queries"))
+    }
 }
 
 /// A reader returned from the VFS.
@@ -23,6 +74,24 @@ pub trait VfsReader: Read + Seek + Send + Sync + 'static {
     ///
     /// This function should try to be as inexpensive as possible.
     fn get_size(&self) -> Result<u64, Error>;
+
+    /// Return the whole content of the entry without copying, if the reader has it mapped or in memory already.
+    ///
+    /// When this returns `Some`, the [AssetCache](crate::AssetCache) keeps the buffer in its bytes tier and hands it
+    /// to [Decoder::decode_shared] directly, rather than reading into a vec.  The default returns `None`.
+    fn mapped_bytes(&self) -> Option<SharedBytes> {
+        None
+    }
+
+    /// A hash identifying the content, if the reader knows one cheaply, for example from a build manifest.  The
+    /// default returns `None`.
+    ///
+    /// The [AssetCache](crate::AssetCache) records this with what it caches, and treats entries whose recorded hash
+    /// differs from the current one as misses.  This is called on every read, so readers shouldn't hash the content
+    /// themselves to provide it.
+    fn content_hash(&self) -> Option<u64> {
+        None
+    }
 }
 
 /// A `Decoder` knows how to get from a reader to a decoded representation in memory.
@@ -36,6 +105,14 @@ pub trait Decoder {
 
     fn decode<R: Read + Seek>(&self, reader: R) -> Result<Self::Output, Self::Error>;
 
+    /// The version of this decoder's output.
+    ///
+    /// Bump this whenever a change to the decoder would change what it produces, so that outputs persisted by older
+    /// versions (for example in a disk tier) are treated as stale.  Defaults to 0.
+    fn version(&self) -> u64 {
+        0
+    }
+
     /// Estimate the cost of a decoded item, usually the in-memory size.
     fn estimate_cost(&self, item: &Self::Output) -> Result<u64, Self::Error>;
 
@@ -47,12 +124,71 @@ pub trait Decoder {
     fn decode_bytes(&self, bytes: &[u8]) -> Result<Self::Output, Self::Error> {
         self.decode(std::io::Cursor::new(bytes))
     }
+
+    /// Decode from a buffer which the output may keep views into, via [SharedBytes::slice] or cloning.
+    ///
+    /// The cache calls this instead of [Decoder::decode_bytes] when it has the bytes in a [SharedBytes], which is
+    /// whenever they are held uncompressed in the bytes tier.  By default this forwards to [Decoder::decode_bytes].
+    fn decode_shared(&self, bytes: SharedBytes) -> Result<Self::Output, Self::Error> {
+        self.decode_bytes(&bytes)
+    }
+}
+
+/// A [Decoder] which takes parameters, for use with keys such as [ParamKey](crate::ParamKey).
+///
+/// Every decoder implements this for `()`, by ignoring the parameters.
+pub trait DecodeWith<P>: Decoder {
+    fn decode_with<R: Read + Seek>(
+        &self,
+        reader: R,
+        params: &P,
+    ) -> Result<Self::Output, Self::Error>;
+
+    /// Like [Decoder::decode_bytes].  By default this forwards to [DecodeWith::decode_with].
+    fn decode_bytes_with(&self, bytes: &[u8], params: &P) -> Result<Self::Output, Self::Error> {
+        self.decode_with(std::io::Cursor::new(bytes), params)
+    }
+
+    /// Like [Decoder::decode_shared].  By default this forwards to [DecodeWith::decode_bytes_with].
+    fn decode_shared_with(
+        &self,
+        bytes: SharedBytes,
+        params: &P,
+    ) -> Result<Self::Output, Self::Error> {
+        self.decode_bytes_with(&bytes, params)
+    }
+}
+
+impl<D: Decoder> DecodeWith<()> for D {
+    fn decode_with<R: Read + Seek>(&self, reader: R, _params: &()) -> Result<D::Output, D::Error> {
+        self.decode(reader)
+    }
+
+    fn decode_bytes_with(&self, bytes: &[u8], _params: &()) -> Result<D::Output, D::Error> {
+        self.decode_bytes(bytes)
+    }
+
+    fn decode_shared_with(&self, bytes: SharedBytes, _params: &()) -> Result<D::Output, D::Error> {
+        self.decode_shared(bytes)
+    }
 }
 
-impl<T: Vfs> Vfs for std::sync::Arc<T> {
+impl<K: ?Sized, T: Vfs<K>> Vfs<K> for std::sync::Arc<T> {
     type Reader = T::Reader;
 
-    fn open(&self, key: &str) -> Result<Self::Reader, Error> {
+    fn open(&self, key: &K) -> Result<Self::Reader, Error> {
         (**self).open(key)
     }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        (**self).list(prefix)
+    }
+
+    fn exists(&self, key: &K) -> Result<bool, Error> {
+        (**self).exists(key)
+    }
+
+    fn metadata(&self, key: &K) -> Result<VfsMetadata, Error> {
+        (**self).metadata(key)
+    }
 }
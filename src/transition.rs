@@ -0,0 +1,337 @@
+//! Switching an [AssetCache] from one working set of assets to another, for example when a game changes scenes.
+//!
+//! The working set is a set of keys which the cache keeps pinned.  [AssetCache::transition_to] loads and pins the keys
+//! which a manifest adds on a background thread, and once they are all loaded, releases the ones it removes, so that
+//! assets shared by both scenes are never unloaded in between.
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::*;
+
+/// The keys an [AssetCache] holds pinned as its working set.
+pub(crate) struct WorkingSet<K: CacheKey + ?Sized> {
+    /// Keys we hold a pin on, with the ids of the pins.
+    pinned: HashMap<Arc<K>, u64>,
+    /// Bumped by every transition, so that older transitions still running know to stop.
+    generation: u64,
+}
+
+impl<K: CacheKey + ?Sized> Default for WorkingSet<K> {
+    fn default() -> Self {
+        WorkingSet {
+            pinned: HashMap::new(),
+            generation: 0,
+        }
+    }
+}
+
+/// Configuration for [AssetCache::transition_to].
+#[derive(Clone, Debug, Default, derive_builder::Builder)]
+pub struct TransitionConfig {
+    /// Remove keys which leave the working set from the bytes and decoded tiers, rather than leaving them to be evicted
+    /// as usual.  Items pinned by something else stay pinned.
+    #[builder(default)]
+    pub drop_removed: bool,
+}
+
+/// A snapshot of how far a transition has got.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TransitionProgress {
+    /// Keys added to the working set which have been loaded and pinned.
+    pub loaded: u64,
+    /// Keys added to the working set which failed to load.
+    pub failed: u64,
+    /// Keys added to the working set.
+    pub total: u64,
+    /// Bytes read from the [Vfs] while loading them.  Items which were already in memory don't count.
+    pub bytes_read: u64,
+    /// Whether the transition has finished, either by completing or by being superseded by a newer one.
+    pub done: bool,
+}
+
+struct TransitionState<K: CacheKey + ?Sized, E> {
+    loaded: AtomicU64,
+    failed: AtomicU64,
+    total: u64,
+    bytes_read: AtomicU64,
+    done: AtomicBool,
+    errors: Mutex<Vec<(K::Owned, AssetCacheError<E>)>>,
+}
+
+impl<K: CacheKey + ?Sized, E> TransitionState<K, E> {
+    fn progress(&self) -> TransitionProgress {
+        TransitionProgress {
+            loaded: self.loaded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            total: self.total,
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            done: self.done.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// Tracks a transition started by [AssetCache::transition_to].
+///
+/// Dropping the handle doesn't stop the transition.
+pub struct TransitionHandle<E, K: CacheKey + ?Sized = str> {
+    state: Arc<TransitionState<K, E>>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl<E, K: CacheKey + ?Sized> TransitionHandle<E, K> {
+    pub fn progress(&self) -> TransitionProgress {
+        self.state.progress()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.done.load(Ordering::Acquire)
+    }
+
+    /// Wait for the transition to finish, returning the keys which failed to load with their errors.
+    pub fn wait(self) -> Vec<(K::Owned, AssetCacheError<E>)> {
+        // The thread only panics if a lock is poisoned, in which case the cache is unusable anyway.
+        let _ = self.thread.join();
+        std::mem::take(&mut *self.state.errors.lock().unwrap())
+    }
+}
+
+impl<E, K: CacheKey + ?Sized> std::fmt::Debug for TransitionHandle<E, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransitionHandle")
+            .field("progress", &self.progress())
+            .finish()
+    }
+}
+
+impl<VfsImpl, DecoderImpl, K> AssetCache<VfsImpl, DecoderImpl, K>
+where
+    VfsImpl: Vfs<K::Source>,
+    K: CacheKey + ?Sized,
+    DecoderImpl: DecodeWith<K::Params> + Send + Sync + 'static,
+    DecoderImpl::Output: 'static,
+    DecoderImpl::Error: Send + 'static,
+{
+    /// Make the keys of `manifest` the cache's working set.
+    ///
+    /// Keys which are new to the working set are loaded and pinned in manifest order on a background thread, through
+    /// the usual tiers, so a concurrent [AssetCache::get] of one of them waits for the same decode rather than
+    /// repeating it.  Once they have all been tried, keys which are no longer in the working set are unpinned and go
+    /// back to normal LRU management, or are dropped from the tiers if [TransitionConfig::drop_removed] is set.  Keys
+    /// which fail to load are left out of the working set, and reported by [TransitionHandle::wait].
+    ///
+    /// Starting a transition while another is running supersedes it: the older one stops after its current key, and
+    /// only the newest releases anything.
+    pub fn transition_to<Q: Borrow<K>>(
+        self: &Arc<Self>,
+        manifest: impl IntoIterator<Item = Q>,
+        config: TransitionConfig,
+    ) -> TransitionHandle<DecoderImpl::Error, K> {
+        let mut target = HashSet::new();
+        let mut ordered = vec![];
+        for k in manifest {
+            let k = self.normalize(k.borrow()).to_shared();
+            if target.insert(k.clone()) {
+                ordered.push(k);
+            }
+        }
+
+        let (generation, added) = {
+            let mut ws = self.working_set.lock().unwrap();
+            ws.generation += 1;
+            ordered.retain(|k| !ws.pinned.contains_key(k));
+            (ws.generation, ordered)
+        };
+
+        let state = Arc::new(TransitionState {
+            loaded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            total: added.len() as u64,
+            bytes_read: AtomicU64::new(0),
+            done: AtomicBool::new(false),
+            errors: Mutex::new(vec![]),
+        });
+
+        let cache = self.clone();
+        let thread_state = state.clone();
+        let thread = std::thread::Builder::new()
+            .name("asset_lru transition".into())
+            .spawn(move || {
+                cache.run_transition(generation, added, target, config, &thread_state);
+                thread_state.done.store(true, Ordering::Release);
+            })
+            .expect("Should be able to spawn the transition thread");
+
+        TransitionHandle { state, thread }
+    }
+
+    fn run_transition(
+        &self,
+        generation: u64,
+        added: Vec<Arc<K>>,
+        target: HashSet<Arc<K>>,
+        config: TransitionConfig,
+        state: &TransitionState<K, DecoderImpl::Error>,
+    ) {
+        for key in added {
+            let mut bytes_read = 0;
+            let res = self.pin_normalized(&key, &mut bytes_read);
+            state.bytes_read.fetch_add(bytes_read, Ordering::Relaxed);
+            let pin_id = match res {
+                Ok((_, pin_id)) => pin_id,
+                Err(e) => {
+                    state.failed.fetch_add(1, Ordering::Relaxed);
+                    state
+                        .errors
+                        .lock()
+                        .unwrap()
+                        .push((K::to_owned_key(&key), e));
+                    continue;
+                }
+            };
+
+            let mut ws = self.working_set.lock().unwrap();
+            // If we've been superseded, or the key is already held, the pin we just took isn't needed.
+            if ws.generation != generation || ws.pinned.contains_key(&key) {
+                self.release_pin(&key, pin_id);
+            } else {
+                ws.pinned.insert(key.clone(), pin_id);
+            }
+            if ws.generation != generation {
+                return;
+            }
+            state.loaded.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let removed = {
+            let mut ws = self.working_set.lock().unwrap();
+            if ws.generation != generation {
+                return;
+            }
+            let (kept, removed) = ws
+                .pinned
+                .drain()
+                .partition::<HashMap<_, _>, _>(|(k, _)| target.contains(k));
+            ws.pinned = kept;
+            removed
+        };
+
+        for (key, pin_id) in removed.iter() {
+            self.release_pin(key, *pin_id);
+        }
+        if config.drop_removed {
+            self.retain(RemovalScope::CACHED, |k| !removed.contains_key(k));
+        }
+    }
+
+    /// The keys of the working set which are currently loaded and pinned, sorted.
+    pub fn working_set(&self) -> Vec<K::Owned>
+    where
+        K::Owned: Ord,
+    {
+        let mut out = self
+            .working_set
+            .lock()
+            .unwrap()
+            .pinned
+            .keys()
+            .map(|k| K::to_owned_key(k))
+            .collect::<Vec<_>>();
+        out.sort_unstable();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::StringDecoder;
+
+    #[test]
+    fn test_transition() {
+        let vfs = Arc::new(MemoryVfs::new());
+        for (k, v) in [
+            ("menu/bg", "bg"),
+            ("shared", "shared"),
+            ("level/map", "map"),
+        ] {
+            vfs.insert(k, v.as_bytes());
+        }
+        let cfg = AssetCacheConfigBuilder::default()
+            .max_bytes_cost(100)
+            .max_single_object_bytes_cost(100)
+            .max_decoded_cost(100)
+            .max_single_object_decoded_cost(100)
+            .build()
+            .unwrap();
+        let cache = Arc::new(
+            AssetCache::new(vfs, StringDecoder, cfg)
+                .with_key_normalizer(DefaultKeyNormalizer::default()),
+        );
+
+        let handle = cache.transition_to(
+            ["menu/bg", "/shared", "missing"],
+            TransitionConfig::default(),
+        );
+        let errors = handle_errors(handle, 3, 2, 8);
+        assert_eq!(errors, vec!["missing"]);
+        assert_eq!(cache.working_set(), vec!["menu/bg", "shared"]);
+        assert_eq!(cache.pinned_cost(), 8);
+
+        // Shared assets stay pinned throughout, and aren't read again.
+        let handle = cache.transition_to(
+            vec!["shared".to_string(), "level/map".to_string()],
+            TransitionConfigBuilder::default()
+                .drop_removed(true)
+                .build()
+                .unwrap(),
+        );
+        assert!(handle_errors(handle, 1, 1, 3).is_empty());
+        assert_eq!(cache.working_set(), vec!["level/map", "shared"]);
+        assert_eq!(
+            cache.pinned_entries(),
+            vec![("level/map".to_string(), 3), ("shared".to_string(), 6)]
+        );
+
+        // Without drop_removed, the old working set is still cached, just no longer pinned.
+        let handle = cache.transition_to(Vec::<String>::new(), TransitionConfig::default());
+        assert!(handle_errors(handle, 0, 0, 0).is_empty());
+        assert!(cache.working_set().is_empty());
+        assert!(cache.pinned_entries().is_empty());
+        let shared = cache.get("shared").unwrap();
+        assert!(Arc::ptr_eq(&shared, &cache.get("shared").unwrap()));
+
+        // Leaving the working set doesn't release pins taken since the cache was cleared.
+        let handle = cache.transition_to(["shared"], TransitionConfig::default());
+        assert!(handle_errors(handle, 1, 1, 0).is_empty());
+        cache.clear(RemovalScope::ALL);
+        let guard = cache.pin("shared").unwrap();
+        let handle = cache.transition_to(Vec::<String>::new(), TransitionConfig::default());
+        assert!(handle_errors(handle, 0, 0, 0).is_empty());
+        assert_eq!(cache.pinned_entries(), vec![("shared".to_string(), 6)]);
+        drop(guard);
+    }
+
+    /// Wait for a transition, check its progress, and return the keys which failed.
+    fn handle_errors(
+        handle: TransitionHandle<std::io::Error>,
+        total: u64,
+        loaded: u64,
+        bytes_read: u64,
+    ) -> Vec<String> {
+        let state = handle.state.clone();
+        let errors = handle.wait();
+        assert_eq!(
+            state.progress(),
+            TransitionProgress {
+                loaded,
+                failed: total - loaded,
+                total,
+                bytes_read,
+                done: true,
+            }
+        );
+        errors.into_iter().map(|(k, _)| k).collect()
+    }
+}